@@ -1,6 +1,21 @@
 use blockchain_demo::cli::cli::Cli;
+use blockchain_demo::core::config::ChainConfig;
+use blockchain_demo::error::Result;
 
-/// "a", "b", "c"  
 fn main() {
-    Cli::start();
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// 交互式命令行，参数与 main 相同，输入 help 查看命令
+///
+/// --session-key 生成本次运行的账户 0 接收出块奖励，代替配置中的 miner_address
+fn run() -> Result<()> {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let session_key = args.iter().any(|arg| arg == "--session-key");
+    args.retain(|arg| arg != "--session-key");
+    let config = ChainConfig::from_args(args)?;
+    Cli::start(config, session_key)
 }
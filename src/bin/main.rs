@@ -1,15 +1,32 @@
-use blockchain_demo::cli::cli::Cli;
+use blockchain_demo::core::account::Account;
+use blockchain_demo::core::config::ChainConfig;
 use blockchain_demo::core::miner::Host;
-use blockchain_demo::core::transaction::Transaction;
+use blockchain_demo::error::Result;
 
-// TODO rocksDb
 fn main() {
-    let mut host = Host::new();
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    // 例如 main --config testnet.toml --data-dir testnet_db
+    let config = ChainConfig::from_args(std::env::args().skip(1))?;
+    let hasher = config.hasher;
+    let mut host = Host::new(config)?;
+
+    let mut alice = Account::generate();
+    alice.sync(&host.get_account(&alice.address)?);
+    let tx = alice.send_to(Account::generate().address, 0, 0, hasher)?;
+    host.add_transaction(tx)?;
+    host.mining()?;
+    let tx = alice.send_to(Account::generate().address, 0, 0, hasher)?;
+    host.add_transaction(tx)?;
+    host.mining()?;
 
-    let tx = Transaction::new([2; 32], [3; 32], 3, 1, 0, "".to_string());
-    host.mining(&mut vec![tx]);
-    let tx = Transaction::new([4; 32], [5; 32], 5, 1, 0, "".to_string());
-    host.mining(&mut vec![tx]);
+    host.print()?;
+    println!("alice: {:?}", host.get_account(&alice.address)?);
 
-    host.print();
+    Ok(())
 }
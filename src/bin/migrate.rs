@@ -0,0 +1,31 @@
+use blockchain_demo::core::bcdb::BlockChainDb;
+use blockchain_demo::core::config::ChainConfig;
+use blockchain_demo::core::migrate;
+use blockchain_demo::error::Result;
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// 把旧版本的数据库转换为当前的 key 布局，参数与 main 相同
+/// 例如 migrate --data-dir blockchain_db
+fn run() -> Result<()> {
+    let config = ChainConfig::from_args(std::env::args().skip(1))?;
+    let db = BlockChainDb::new_db(&config.data_dir)?;
+    if !migrate::is_legacy(db.as_ref())? {
+        println!("nothing to migrate");
+        return Ok(());
+    }
+
+    // 检查、转换并打开一次链，失败时数据库不变
+    let stats = migrate::migrate(db, &config)?;
+    println!(
+        "migrated {} blocks, {} trie nodes",
+        stats.blocks, stats.nodes
+    );
+
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use blockchain_demo::core::config::ChainConfig;
+use blockchain_demo::core::miner::Host;
+use blockchain_demo::error::Result;
+use blockchain_demo::net::node::Node;
+use blockchain_demo::rpc::server::RpcServer;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// 启动 p2p 节点和 JSON-RPC 服务，从 peers 同步之后一直运行，参数与 main 相同
+/// 例如 node --data-dir node2_db --listen-addr 127.0.0.1:7879 --peers 127.0.0.1:7878 --rpc-addr 127.0.0.1:7881
+fn run() -> Result<()> {
+    let config = ChainConfig::from_args(std::env::args().skip(1))?;
+    let rpc_addr = config.rpc_addr.clone();
+    let node = Arc::new(Node::start(Host::new(config)?)?);
+    println!("listening on {}", node.local_addr());
+    let rpc = RpcServer::start(node.clone(), &rpc_addr)?;
+    println!("rpc listening on {}", rpc.local_addr());
+
+    loop {
+        thread::sleep(Duration::from_secs(60));
+        let status = node.sync_status()?;
+        println!(
+            "height {}, headers {}, {} peers",
+            status.height,
+            status.best_header_height,
+            node.peer_count()
+        );
+    }
+}
@@ -1,20 +1,43 @@
-use crate::cli::command;
+use crate::cli::command::{self, Command, CommandError, Session};
+use crate::cli::completer::CommandHelper;
+use crate::core::config::ChainConfig;
+use crate::error::Result;
+use crate::utils::hex;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
+use std::sync::Arc;
 
 pub struct Cli;
 
 const CMD_HISTORY_PATH: &str = "command_history.txt";
 
+/// help 不带参数时列出所有命令
+fn print_help(commands: &[Arc<dyn Command>]) {
+    for cmd in commands {
+        println!(
+            "{:<8} {:<32} {}",
+            cmd.name(),
+            cmd.usage(),
+            cmd.description()
+        );
+    }
+    println!("{:<8} {:<32} show help of a command", "help", "[command]");
+    println!("{:<8} {:<32} exit", "quit", "");
+}
+
 impl Cli {
-    pub fn start() {
-        let mut editor = Editor::<()>::new();
+    /// 打开 config.data_dir 下的数据库，session_key 见 Session::new
+    pub fn start(config: ChainConfig, session_key: bool) -> Result<()> {
+        let mut session = Session::new(config, session_key)?;
+        println!("miner address: {}", hex::encode(&session.miner_address()));
+
+        let (commands, cmd_alias) = command::get_commands();
+        let mut editor = Editor::<CommandHelper>::new();
+        editor.set_helper(Some(CommandHelper::new(&commands)));
         editor
             .load_history(CMD_HISTORY_PATH)
             .unwrap_or_else(|e| println!("No previous history {}", e));
 
-        let (_, cmd_alias) = command::get_commands();
-
         loop {
             let res_line = editor.readline(">>");
             match res_line {
@@ -26,14 +49,21 @@ impl Cli {
 
                     // 第一个参数为命令别名
                     match cmd_alias.get(&params[0]) {
-                        Some(cmd) => {
-                            cmd.execute(&params);
-                        }
-                        None => match params[0] {
-                            "" => continue,
-                            "quit" | "q!" => break,
-                            "help" | "h" => println!("Print help command"),
-                            x => println!("Unknown command: {}", x),
+                        Some(cmd) => match command::run(cmd.as_ref(), &mut session, &params) {
+                            Ok(()) => {}
+                            Err(CommandError::Usage(e)) => {
+                                println!("{}\nusage: {} {}", e, cmd.name(), cmd.usage())
+                            }
+                            Err(e) => println!("Error: {}", e),
+                        },
+                        None => match params[..] {
+                            ["quit"] | ["q!"] => break,
+                            ["help"] | ["h"] => print_help(&commands),
+                            ["help", name] | ["h", name] => match cmd_alias.get(name) {
+                                Some(cmd) => println!("{}", cmd.help()),
+                                None => println!("Unknown command: {}", name),
+                            },
+                            _ => println!("Unknown command: {}", params[0]),
                         },
                     }
 
@@ -54,6 +84,9 @@ impl Cli {
             }
         }
 
-        editor.save_history(CMD_HISTORY_PATH).unwrap();
+        if let Err(e) = editor.save_history(CMD_HISTORY_PATH) {
+            eprintln!("failed to save history: {}", e);
+        }
+        Ok(())
     }
 }
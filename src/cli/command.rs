@@ -1,11 +1,156 @@
-use std::{collections::HashMap, sync::Arc};
+use crate::core::account::Account;
+use crate::core::config::ChainConfig;
+use crate::core::miner::Host;
+use crate::error::Error;
+use crate::utils::hex;
+use std::{collections::HashMap, fmt, sync::Arc};
+
+/// 命令执行失败的原因
+#[derive(Debug)]
+pub enum CommandError {
+    /// 参数不对，打印命令的用法
+    Usage(String),
+    Chain(Error),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Usage(e) => write!(f, "{}", e),
+            CommandError::Chain(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<Error> for CommandError {
+    fn from(e: Error) -> Self {
+        CommandError::Chain(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, CommandError>;
+
+fn usage<T>(message: &str) -> Result<T> {
+    Err(CommandError::Usage(message.to_string()))
+}
+
+/// REPL 的状态，命令通过它访问链和账户
+pub struct Session {
+    host: Host,
+    /// 本次运行生成的账户，私钥只保存在内存中，退出后丢失
+    accounts: Vec<Account>,
+}
+
+impl Session {
+    /// 打开 config.data_dir 下的数据库，出块奖励给 config.miner_address
+    ///
+    /// session_key 为 true 时生成账户 0 代替 config.miner_address ，私钥退出后丢失
+    pub fn new(mut config: ChainConfig, session_key: bool) -> crate::error::Result<Session> {
+        let mut accounts = Vec::new();
+        if session_key {
+            let miner = Account::generate();
+            config.miner_address = miner.address;
+            accounts.push(miner);
+        }
+        Ok(Session {
+            host: Host::new(config)?,
+            accounts,
+        })
+    }
+
+    /// 出块奖励的接收地址
+    pub fn miner_address(&self) -> [u8; 32] {
+        self.host.blockchain().config().miner_address
+    }
+
+    /// 账户序号或十六进制地址
+    fn address(&self, s: &str) -> Result<[u8; 32]> {
+        if let Ok(i) = s.parse::<usize>() {
+            return match self.accounts.get(i) {
+                Some(account) => Ok(account.address),
+                None => usage(&format!("no account {}", i)),
+            };
+        }
+        match hex::decode_32(s) {
+            Some(address) => Ok(address),
+            None => usage(&format!("invalid account or address: {}", s)),
+        }
+    }
+
+    /// 可以签名的账户，nonce 和余额包括交易池中还没有打包的交易，不包括没有成熟的 coinbase
+    fn sender(&self, s: &str) -> Result<Account> {
+        let address = self.address(s)?;
+        let mut account = match self.accounts.iter().find(|a| a.address == address) {
+            Some(account) => account.clone(),
+            None => return usage(&format!("not a local account: {}", s)),
+        };
+        let chain = self.host.blockchain();
+        account.sync(&chain.get_account(&address)?);
+        let immature = chain.immature_balance(&address)?;
+        account.balance = account.balance.saturating_sub(immature);
+        for tx in self.host.mempool().transactions() {
+            if tx.from == address {
+                account.nonce = account.nonce.max(tx.nonce);
+                account.balance = account
+                    .balance
+                    .saturating_sub(tx.amount.saturating_add(tx.fee));
+            }
+        }
+        Ok(account)
+    }
+}
 
 pub trait Command {
+    /// 第一个是命令名，其余是别名
     fn get_aliases(&self) -> Vec<&'static str>;
 
-    fn execute(&self, params: &[&str]) {
-        let _ = params;
-        println!("default, do nothing");
+    /// 命令名之后的参数，例如 "<to> <amount> <fee>"
+    fn usage(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    /// 参数个数的范围，不包括命令名
+    fn arg_count(&self) -> (usize, usize);
+
+    /// 第一个参数必须是其中之一，也用于补全
+    fn subcommands(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
+    /// params[0] 是命令名，参数已经用 check 检查过
+    fn execute(&self, session: &mut Session, params: &[&str]) -> Result<()>;
+
+    fn name(&self) -> &'static str {
+        self.get_aliases()[0]
+    }
+
+    /// 检查参数个数和子命令
+    fn check(&self, params: &[&str]) -> Result<()> {
+        let (min, max) = self.arg_count();
+        let n = params.len().saturating_sub(1);
+        if n < min || n > max {
+            return usage("wrong number of arguments");
+        }
+        let subcommands = self.subcommands();
+        if !subcommands.is_empty() && !subcommands.contains(&params[1]) {
+            return usage(&format!("unknown subcommand: {}", params[1]));
+        }
+        Ok(())
+    }
+
+    /// help <命令> 打印的内容
+    fn help(&self) -> String {
+        let aliases = self.get_aliases();
+        let mut s = format!(
+            "{} {}\n    {}",
+            self.name(),
+            self.usage(),
+            self.description()
+        );
+        if aliases.len() > 1 {
+            s += &format!("\n    aliases: {}", aliases[1..].join(", "));
+        }
+        s
     }
 }
 
@@ -14,11 +159,25 @@ pub fn parse(cmd_str: &str) -> Vec<&str> {
     cmd_str.split_ascii_whitespace().collect()
 }
 
-pub fn get_commands() -> (
-    Vec<Arc<dyn Command>>,
-    HashMap<&'static str, Arc<dyn Command>>,
-) {
-    let commands: Vec<Arc<dyn Command>> = vec![Arc::new(AccountCommand {})];
+/// 检查参数后执行
+pub fn run(cmd: &dyn Command, session: &mut Session, params: &[&str]) -> Result<()> {
+    cmd.check(params)?;
+    cmd.execute(session, params)
+}
+
+/// 别名 -> 命令
+pub type CommandMap = HashMap<&'static str, Arc<dyn Command>>;
+
+pub fn get_commands() -> (Vec<Arc<dyn Command>>, CommandMap) {
+    let commands: Vec<Arc<dyn Command>> = vec![
+        Arc::new(AccountCommand),
+        Arc::new(SendCommand),
+        Arc::new(MineCommand),
+        Arc::new(BlockCommand),
+        Arc::new(TxCommand),
+        Arc::new(ChainCommand),
+        Arc::new(MempoolCommand),
+    ];
 
     let mut alias_to_cmd = HashMap::new();
     for command in &commands {
@@ -30,15 +189,354 @@ pub fn get_commands() -> (
     (commands, alias_to_cmd)
 }
 
+fn parse_u64(s: &str, name: &str) -> Result<u64> {
+    match s.parse() {
+        Ok(n) => Ok(n),
+        Err(_) => usage(&format!("invalid {}: {}", name, s)),
+    }
+}
+
+fn parse_hash(s: &str) -> Result<[u8; 32]> {
+    match hex::decode_32(s) {
+        Some(hash) => Ok(hash),
+        None => usage(&format!("invalid hash: {}", s)),
+    }
+}
+
 pub struct AccountCommand;
 
 impl Command for AccountCommand {
     fn get_aliases(&self) -> Vec<&'static str> {
-        vec!["a", "b", "c"]
+        vec!["account", "a"]
+    }
+
+    fn usage(&self) -> &'static str {
+        "new | list | balance [account]"
+    }
+
+    fn description(&self) -> &'static str {
+        "generate an account, list local accounts, or show the balance of an account (index or address)"
+    }
+
+    fn arg_count(&self) -> (usize, usize) {
+        (1, 2)
+    }
+
+    fn subcommands(&self) -> Vec<&'static str> {
+        vec!["new", "list", "balance"]
+    }
+
+    fn execute(&self, session: &mut Session, params: &[&str]) -> Result<()> {
+        match params[1..] {
+            ["new"] => {
+                let account = Account::generate();
+                println!(
+                    "{} {}",
+                    session.accounts.len(),
+                    hex::encode(&account.address)
+                );
+                session.accounts.push(account);
+            }
+            ["list"] => {
+                for (i, account) in session.accounts.iter().enumerate() {
+                    let state = session.host.get_account(&account.address)?;
+                    println!("{} {} {}", i, hex::encode(&account.address), state.balance);
+                }
+            }
+            ["balance"] | ["balance", _] => {
+                let address = session.address(params.get(2).unwrap_or(&"0"))?;
+                let chain = session.host.blockchain();
+                let state = chain.get_account(&address)?;
+                println!("address:  {}", hex::encode(&address));
+                println!("balance:  {}", state.balance);
+                println!("immature: {}", chain.immature_balance(&address)?);
+                println!("nonce:    {}", state.nonce);
+            }
+            _ => return usage("wrong number of arguments"),
+        }
+        Ok(())
+    }
+}
+
+pub struct SendCommand;
+
+impl Command for SendCommand {
+    fn get_aliases(&self) -> Vec<&'static str> {
+        vec!["send", "s"]
+    }
+
+    fn usage(&self) -> &'static str {
+        "<to> <amount> <fee> [from]"
+    }
+
+    fn description(&self) -> &'static str {
+        "send from a local account (default 0) to an account or address, the transaction waits in the mempool"
+    }
+
+    fn arg_count(&self) -> (usize, usize) {
+        (3, 4)
+    }
+
+    fn execute(&self, session: &mut Session, params: &[&str]) -> Result<()> {
+        let to = session.address(params[1])?;
+        let amount = parse_u64(params[2], "amount")?;
+        let fee = parse_u64(params[3], "fee")?;
+        let mut from = session.sender(params.get(4).unwrap_or(&"0"))?;
+
+        let hasher = session.host.blockchain().config().hasher;
+        let tx = from.send_to(to, amount, fee, hasher)?;
+        let hash = tx.hash;
+        session.host.add_transaction(tx)?;
+        println!("{}", hex::encode(&hash));
+        Ok(())
+    }
+}
+
+pub struct MineCommand;
+
+impl Command for MineCommand {
+    fn get_aliases(&self) -> Vec<&'static str> {
+        vec!["mine", "m"]
+    }
+
+    fn usage(&self) -> &'static str {
+        "[n]"
+    }
+
+    fn description(&self) -> &'static str {
+        "mine n blocks (default 1) with transactions from the mempool, rewards go to the miner address"
+    }
+
+    fn arg_count(&self) -> (usize, usize) {
+        (0, 1)
+    }
+
+    fn execute(&self, session: &mut Session, params: &[&str]) -> Result<()> {
+        let n = match params.get(1) {
+            Some(s) => parse_u64(s, "count")?,
+            None => 1,
+        };
+        for _ in 0..n {
+            session.host.mining()?;
+            let chain = session.host.blockchain();
+            println!("{} {}", chain.curr_height, hex::encode(&chain.curr_hash));
+        }
+        Ok(())
+    }
+}
+
+pub struct BlockCommand;
+
+impl Command for BlockCommand {
+    fn get_aliases(&self) -> Vec<&'static str> {
+        vec!["block", "b"]
+    }
+
+    fn usage(&self) -> &'static str {
+        "<hash|height>"
+    }
+
+    fn description(&self) -> &'static str {
+        "print a block by hash, or the block at a height of the main chain"
     }
 
-    fn execute(&self, params: &[&str]) {
-        let s: String = params.join(" ");
-        println!("execute account command: {}", s);
+    fn arg_count(&self) -> (usize, usize) {
+        (1, 1)
+    }
+
+    fn execute(&self, session: &mut Session, params: &[&str]) -> Result<()> {
+        let chain = session.host.blockchain();
+        // hash 是 64 个字符，不会被当作高度
+        let b = match params[1].parse::<u64>() {
+            Ok(height) => chain.get_block_by_height(height)?,
+            Err(_) => chain.get_block(&parse_hash(params[1])?)?,
+        };
+        match b {
+            Some(b) => println!("{:?}", b),
+            None => println!("block not found"),
+        }
+        Ok(())
+    }
+}
+
+pub struct TxCommand;
+
+impl Command for TxCommand {
+    fn get_aliases(&self) -> Vec<&'static str> {
+        vec!["tx", "t"]
+    }
+
+    fn usage(&self) -> &'static str {
+        "<hash>"
+    }
+
+    fn description(&self) -> &'static str {
+        "print a transaction from the main chain or the mempool"
+    }
+
+    fn arg_count(&self) -> (usize, usize) {
+        (1, 1)
+    }
+
+    fn execute(&self, session: &mut Session, params: &[&str]) -> Result<()> {
+        let hash = parse_hash(params[1])?;
+        let host = &session.host;
+        if let Some(tx) = host.mempool().get(&hash) {
+            println!("{:?}\npending", tx);
+            return Ok(());
+        }
+        let chain = host.blockchain();
+        match (chain.get_tx_location(&hash)?, chain.get_transaction(&hash)?) {
+            (Some((block_hash, index)), Some(tx)) => {
+                println!(
+                    "{:?}\nblock {} index {}",
+                    tx,
+                    hex::encode(&block_hash),
+                    index
+                )
+            }
+            _ => println!("transaction not found"),
+        }
+        Ok(())
+    }
+}
+
+pub struct ChainCommand;
+
+impl Command for ChainCommand {
+    fn get_aliases(&self) -> Vec<&'static str> {
+        vec!["chain", "c"]
+    }
+
+    fn usage(&self) -> &'static str {
+        "print"
+    }
+
+    fn description(&self) -> &'static str {
+        "print the main chain from genesis to tail"
+    }
+
+    fn arg_count(&self) -> (usize, usize) {
+        (1, 1)
+    }
+
+    fn subcommands(&self) -> Vec<&'static str> {
+        vec!["print"]
+    }
+
+    fn execute(&self, session: &mut Session, _params: &[&str]) -> Result<()> {
+        session.host.print()?;
+        Ok(())
+    }
+}
+
+pub struct MempoolCommand;
+
+impl Command for MempoolCommand {
+    fn get_aliases(&self) -> Vec<&'static str> {
+        vec!["mempool", "p"]
+    }
+
+    fn usage(&self) -> &'static str {
+        ""
+    }
+
+    fn description(&self) -> &'static str {
+        "list transactions waiting in the mempool"
+    }
+
+    fn arg_count(&self) -> (usize, usize) {
+        (0, 0)
+    }
+
+    fn execute(&self, session: &mut Session, _params: &[&str]) -> Result<()> {
+        let mempool = session.host.mempool();
+        println!("{} transactions, {} bytes", mempool.len(), mempool.size());
+        for tx in mempool.transactions() {
+            println!(
+                "{} from {} to {} amount {} fee {} nonce {}",
+                hex::encode(&tx.hash),
+                hex::encode(&tx.from),
+                hex::encode(&tx.to),
+                tx.amount,
+                tx.fee,
+                tx.nonce
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_commands, parse, run, CommandError, Result, Session};
+    use crate::core::account::Account;
+    use crate::core::blockchain::BlockChain;
+    use crate::core::config::ChainConfig;
+    use crate::core::miner::Host;
+    use crate::core::storage::MemoryStorage;
+    use crate::utils::hex;
+    use std::sync::Arc;
+
+    fn exec(session: &mut Session, line: &str) -> Result<()> {
+        let (_, cmds) = get_commands();
+        let params = parse(line);
+        run(cmds[params[0]].as_ref(), session, &params)
+    }
+
+    #[test]
+    fn commands_work() {
+        let miner = Account::generate();
+        let miner_address = miner.address;
+        let config = ChainConfig {
+            miner_address,
+            coinbase_maturity: 1,
+            ..ChainConfig::default()
+        };
+        let chain = BlockChain::with_storage(Arc::new(MemoryStorage::new()), config).unwrap();
+        let mut session = Session {
+            host: Host::with_blockchain(chain),
+            accounts: vec![miner],
+        };
+        let s = &mut session;
+        assert_eq!(s.miner_address(), miner_address);
+
+        // 参数检查
+        for bad in [
+            "account",
+            "account foo",
+            "a list 1",
+            "send 1 2",
+            "mine x",
+            "block zz",
+            "chain",
+            "p 1",
+        ] {
+            match exec(s, bad) {
+                Err(CommandError::Usage(_)) => {}
+                other => panic!("{}: {:?}", bad, other),
+            }
+        }
+        exec(s, "account new").unwrap();
+        match exec(s, "send 1 1 0") {
+            Err(CommandError::Chain(_)) => {}
+            other => panic!("{:?}", other),
+        }
+        exec(s, "mine 2").unwrap();
+        exec(s, "send 1 10 1").unwrap();
+        exec(s, "send 1 10 1").unwrap();
+        // 第二笔的 nonce 接在交易池中的第一笔之后
+        assert_eq!(s.host.mempool().len(), 2);
+        exec(s, "mine").unwrap();
+        assert_eq!(s.host.mempool().len(), 0);
+        let to = s.accounts[1].address;
+        assert_eq!(s.host.get_account(&to).unwrap().balance, 20);
+
+        let hash = hex::encode(&s.host.blockchain().curr_hash);
+        exec(s, &format!("block {}", hash)).unwrap();
+        exec(s, "b 3").unwrap();
+        exec(s, "chain print").unwrap();
+        exec(s, "account balance 1").unwrap();
+        exec(s, "mempool").unwrap();
     }
 }
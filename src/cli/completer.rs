@@ -0,0 +1,100 @@
+use crate::cli::command::Command;
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+use std::sync::Arc;
+
+/// REPL 内置的命令，不在 get_commands 中
+const BUILTINS: [&str; 2] = ["help", "quit"];
+
+/// 补全命令名、子命令，以及 help 之后的命令名
+pub struct CommandHelper {
+    /// 命令的别名（第一个是命令名）和子命令
+    commands: Vec<(Vec<&'static str>, Vec<&'static str>)>,
+}
+
+impl CommandHelper {
+    pub fn new(commands: &[Arc<dyn Command>]) -> CommandHelper {
+        CommandHelper {
+            commands: commands
+                .iter()
+                .map(|c| (c.get_aliases(), c.subcommands()))
+                .collect(),
+        }
+    }
+
+    /// 光标前最后一个词的起始位置和候选
+    fn candidates(&self, line: &str) -> (usize, Vec<String>) {
+        let start = line.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let prefix = &line[start..];
+        let words: Vec<&str> = line[..start].split_whitespace().collect();
+
+        let names = || {
+            self.commands
+                .iter()
+                .map(|(aliases, _)| aliases[0])
+                .chain(BUILTINS.iter().copied())
+        };
+        let options: Vec<&str> = match words[..] {
+            [] => names().collect(),
+            ["help"] | ["h"] => names().collect(),
+            [cmd] => self
+                .commands
+                .iter()
+                .find(|(aliases, _)| aliases.contains(&cmd))
+                .map_or(Vec::new(), |(_, subcommands)| subcommands.clone()),
+            _ => Vec::new(),
+        };
+        let matches = options
+            .into_iter()
+            .filter(|o| o.starts_with(prefix))
+            .map(|o| o.to_string())
+            .collect();
+        (start, matches)
+    }
+}
+
+impl Completer for CommandHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.candidates(&line[..pos]))
+    }
+}
+
+impl Hinter for CommandHelper {
+    type Hint = String;
+}
+
+impl Highlighter for CommandHelper {}
+
+impl Validator for CommandHelper {}
+
+impl Helper for CommandHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::CommandHelper;
+    use crate::cli::command::get_commands;
+
+    #[test]
+    fn complete_works() {
+        let (commands, _) = get_commands();
+        let helper = CommandHelper::new(&commands);
+        assert_eq!(
+            helper.candidates("m"),
+            (0, vec!["mine".to_string(), "mempool".to_string()])
+        );
+        assert_eq!(helper.candidates("a b"), (2, vec!["balance".to_string()]));
+        assert_eq!(helper.candidates("chain "), (6, vec!["print".to_string()]));
+        assert_eq!(helper.candidates("help ch"), (5, vec!["chain".to_string()]));
+        assert!(helper.candidates("send 1 ").1.is_empty());
+    }
+}
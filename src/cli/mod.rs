@@ -1,2 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod cli;
 mod command;
+mod completer;
@@ -1,58 +1,98 @@
 use crate::core::transaction::Transaction;
-use crate::utils::coder;
+use crate::core::wire;
+use crate::error::{Error, Result};
+use crate::utils::coder::Hasher;
+use crate::utils::keypair::KeyPair;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct Account {
     pub nonce: u64,
     /// 剩余金额
     pub balance: u64,
+    /// Ed25519 公钥
     pub address: [u8; 32],
+    /// 状态树中的 hash ，StateDb 写入时用链的 hash 函数计算，钱包账户通过 sync 更新
     pub hash: [u8; 32],
-    /// 私钥
+    /// 私钥，不参与序列化
+    #[serde(skip)]
     private: [u8; 32],
 }
 
 impl Account {
-    pub fn new(address: [u8; 32], private: [u8; 32]) -> Account {
-        let mut account = Account {
+    /// address 由私钥推导
+    pub fn new(private: [u8; 32]) -> Result<Account> {
+        let keypair = KeyPair::from_private(&private)?;
+        Ok(Self::with_keypair(&keypair))
+    }
+
+    fn with_keypair(keypair: &KeyPair) -> Account {
+        Account {
+            nonce: 0,
+            balance: 0,
+            address: keypair.address(),
+            hash: [0; 32],
+            private: keypair.private(),
+        }
+    }
+
+    /// 只有状态没有私钥，不能签名，用于 StateDb
+    pub fn with_address(address: [u8; 32]) -> Account {
+        Account {
             nonce: 0,
             balance: 0,
             address,
-            // set_hash
             hash: [0; 32],
-            private,
-        };
-        account.set_hash();
+            private: [0; 32],
+        }
+    }
+
+    /// 随机生成私钥
+    pub fn generate() -> Account {
+        Self::with_keypair(&KeyPair::generate())
+    }
 
-        account
+    /// 对 payload() 计算，不包括 hash
+    pub(crate) fn set_hash(&mut self, hasher: Hasher) {
+        self.hash = hasher.hash(&self.payload());
     }
 
-    fn set_hash(&mut self) {
-        let account_data = coder::serialize(&self);
-        let mut hash = coder::get_hash(&account_data);
+    /// hash 是否与内容一致，从状态树读取的账户可以检查
+    pub fn verify_hash(&self, hasher: Hasher) -> bool {
+        self.hash == hasher.hash(&self.payload())
+    }
+
+    /// 不包括 hash 的内容，见 wire::encode_account_payload
+    pub fn payload(&self) -> Vec<u8> {
+        wire::encode_account_payload(self)
+    }
 
-        self.hash = hash;
+    /// 用链上的状态更新 nonce 和余额
+    pub fn sync(&mut self, state: &Account) {
+        self.nonce = state.nonce;
+        self.balance = state.balance;
+        self.hash = state.hash;
     }
 
-    pub fn send_to(&mut self, to: [u8; 32], amount: u64, fee: u64) -> Result<Transaction, String> {
-        if amount + fee > self.balance {
-            return Err("amount + fee > balance".to_string());
+    /// hasher 是链的 hash 函数，用于计算交易的 hash
+    pub fn send_to(
+        &mut self,
+        to: [u8; 32],
+        amount: u64,
+        fee: u64,
+        hasher: Hasher,
+    ) -> Result<Transaction> {
+        match amount.checked_add(fee) {
+            Some(cost) if cost <= self.balance => {}
+            _ => return Err(Error::Account("amount + fee > balance".to_string())),
         }
 
+        let mut tx = Transaction::new(self.address, to, amount, fee, self.nonce + 1, hasher);
+        tx.sign(&KeyPair::from_private(&self.private)?)?;
+
         self.balance -= amount;
         self.balance -= fee;
         self.nonce += 1;
-        self.set_hash();
-
-        let tx = Transaction::new(
-            self.address,
-            to,
-            amount,
-            fee,
-            self.nonce,
-            "sign".to_string(),
-        );
 
         Ok(tx)
     }
@@ -1,39 +1,107 @@
-use crate::utils::key::MyKey;
-use leveldb::database::Database;
-use leveldb::kv::KV;
-use leveldb::options::{Options, ReadOptions, WriteOptions};
+/// 数据库的布局，key 见 utils::key::DbKey
+///
+/// - b + block hash -> Block
+/// - h + block hash -> 累计工作量（32 字节大端）+ 区块头（wire 编码），同步时区块头先于区块体写入
+/// - n + height（8 字节大端）-> 主链 block hash
+/// - t + tx hash -> 主链 (block hash, 交易在区块中的位置)
+/// - s + node hash -> trie 节点
+/// - m + "tail" -> tail 的 block hash ；m + "best_header" -> 累计工作量最大的区块头 hash ；
+///   m + "version" -> 布局版本；m + "recover" -> 存在时打开链时检查整条主链
+///
+/// 旧版本的数据库（key 为 32 字节的 MyKey）用 migrate 工具转换。
+///
+use crate::core::storage::{self, Storage, WriteBatch};
+use crate::error::{Error, Result};
+use crate::utils::coder;
+use crate::utils::key::{Column, DbKey};
+use std::path::Path;
+use std::sync::Arc;
 use std::{env, fs};
 
+/// 当前的数据库布局版本
+pub const DB_VERSION: u32 = 1;
+
 pub struct BlockChainDb;
 
 impl BlockChainDb {
-    pub fn new_db(path: &str) -> Database<MyKey> {
-        let mut dir = env::current_dir().unwrap();
-        // 加 \path
-        dir.push(path);
+    /// 打开 path ，相对路径相对于当前目录，后端见 storage::open
+    pub fn new_db(path: &Path) -> Result<Arc<dyn Storage>> {
+        let dir = env::current_dir()?.join(path);
         println!("db location: {}", dir.display());
 
-        let path_buf = dir.clone();
-        // move
-        fs::create_dir_all(dir).unwrap();
+        fs::create_dir_all(&dir)?;
+        storage::open(dir.as_path())
+    }
+
+    /// 写入操作都先放进 batch ，由调用者一次性提交
+    pub fn write_db(batch: &mut WriteBatch, k: DbKey, v: &[u8]) {
+        batch.put(k.as_bytes(), v);
+    }
+
+    pub fn delete_db(batch: &mut WriteBatch, k: DbKey) {
+        batch.delete(k.as_bytes());
+    }
+
+    pub fn read_db(db: &dyn Storage, k: DbKey) -> Result<Option<Vec<u8>>> {
+        db.get(k.as_bytes())
+    }
+
+    pub fn write_version(batch: &mut WriteBatch) {
+        Self::write_db(batch, DbKey::meta("version"), &DB_VERSION.to_be_bytes());
+    }
+
+    /// 没有写入版本的是空数据库或旧版本的数据库
+    pub fn read_version(db: &dyn Storage) -> Result<Option<u32>> {
+        match Self::read_db(db, DbKey::meta("version"))? {
+            Some(v) if v.len() == 4 => Ok(Some(u32::from_be_bytes([v[0], v[1], v[2], v[3]]))),
+            Some(_) => Err(Error::Codec("bad database version".to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// 下一次打开链时检查并修复整条主链，例如 migrate 转换后的数据库
+    pub fn write_recover(batch: &mut WriteBatch) {
+        Self::write_db(batch, DbKey::meta("recover"), &[]);
+    }
+
+    pub fn delete_recover(batch: &mut WriteBatch) {
+        Self::delete_db(batch, DbKey::meta("recover"));
+    }
+
+    pub fn needs_recover(db: &dyn Storage) -> Result<bool> {
+        Ok(Self::read_db(db, DbKey::meta("recover"))?.is_some())
+    }
+
+    /// 主链 height -> block hash
+    pub fn write_height(batch: &mut WriteBatch, height: u64, hash: &[u8; 32]) {
+        let k = DbKey::number(Column::Height, height);
+        Self::write_db(batch, k, &coder::serialize(hash));
+    }
+
+    pub fn delete_height(batch: &mut WriteBatch, height: u64) {
+        Self::delete_db(batch, DbKey::number(Column::Height, height));
+    }
+
+    pub fn read_height(db: &dyn Storage, height: u64) -> Result<Option<[u8; 32]>> {
+        Self::read_db(db, DbKey::number(Column::Height, height))?
+            .map(|v| coder::deserialize(&v))
+            .transpose()
+    }
 
-        let mut options = Options::new();
-        options.create_if_missing = true;
-        Database::open(path_buf.as_path(), options)
-            .unwrap_or_else(|e| panic!("failed to open database: {:?}", e))
+    /// 主链 tx hash -> (block hash, 交易在区块中的位置)
+    pub fn write_tx_index(batch: &mut WriteBatch, tx_hash: &[u8; 32], hash: &[u8; 32], pos: u64) {
+        let k = DbKey::new(Column::Tx, tx_hash);
+        Self::write_db(batch, k, &coder::serialize(&(hash, pos)));
     }
 
-    pub fn write_db(db: &mut Database<MyKey>, k: MyKey, v: &[u8]) {
-        let opts = WriteOptions::new();
-        db.put(opts, k, &v)
-            .unwrap_or_else(|e| panic!("failed to write block to database: {:?}", e));
+    pub fn delete_tx_index(batch: &mut WriteBatch, tx_hash: &[u8; 32]) {
+        Self::delete_db(batch, DbKey::new(Column::Tx, tx_hash));
     }
 
-    pub fn read_db(db: &mut Database<MyKey>, k: MyKey) -> Option<Vec<u8>> {
-        let opts = ReadOptions::new();
-        db.get(opts, k).unwrap_or_else(|e| {
-            eprintln!("failed to read from database: {}", e);
-            None
-        })
+    pub fn read_tx_index(db: &dyn Storage, tx_hash: &[u8; 32]) -> Result<Option<([u8; 32], u64)>> {
+        let k = DbKey::new(Column::Tx, tx_hash);
+        Self::read_db(db, k)?
+            .map(|v| coder::deserialize(&v))
+            .transpose()
     }
 }
@@ -4,10 +4,14 @@
 ///
 ///
 use crate::core::transaction::Transaction;
-use crate::utils::coder;
+use crate::core::wire;
+use crate::utils::coder::Hasher;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 
+/// 默认的区块最大字节数（wire 编码后），见 ChainConfig.max_block_size
+pub const MAX_BLOCK_SIZE: usize = 1024 * 1024;
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct BlockHeader {
     /// 区块在链中的长度位置
@@ -25,6 +29,13 @@ pub struct BlockHeader {
     pub state_root: [u8; 32],
 }
 
+impl BlockHeader {
+    /// header_hash ，用链的 hash 函数对 wire 编码计算
+    pub fn hash(&self, hasher: Hasher) -> [u8; 32] {
+        hasher.hash(&wire::encode_header(self))
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Block {
     pub header: BlockHeader,
@@ -34,6 +45,35 @@ pub struct Block {
     pub transactions: Vec<Transaction>,
 }
 
+/// 交易在区块中的 merkle 证明
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    /// 交易在区块中的位置，每一位决定该层是左节点还是右节点
+    pub index: u64,
+    pub siblings: Vec<[u8; 32]>,
+}
+
+/// 验证交易在 tx_root（BlockHeader.tx_hash）对应的区块中，leaf 是交易的 witness_hash
+pub fn verify_merkle_proof(
+    leaf: &[u8; 32],
+    proof: &MerkleProof,
+    tx_root: &[u8; 32],
+    hasher: Hasher,
+) -> bool {
+    let mut hash = *leaf;
+    let mut index = proof.index;
+    for sibling in &proof.siblings {
+        hash = if index & 1 == 0 {
+            Block::merkle_merge(&hash, sibling, hasher)
+        } else {
+            Block::merkle_merge(sibling, &hash, hasher)
+        };
+        index /= 2;
+    }
+
+    index == 0 && hash == *tx_root
+}
+
 impl Block {
     /// Merkle Tree 算法
     /// https://en.wikipedia.org/wiki/Merkle_tree
@@ -41,16 +81,28 @@ impl Block {
     /// /note/attach/Merkle_Tree.svg
     /// - 平衡二叉树：将数据块计算的哈希值两两配对，如果是奇数个数，最后一个自己与自己配对。
     /// - 任何底层数据块的变化，最终都会传导到根哈希。
+    ///
     /// 若n为数据块的个数，则空间存储复杂度：O(n)，计算复杂度：O(n)，检索那个数据块错误的复杂度：O(log2n)，
     /// 所以主要是用于区块链数据校验。
     ///
     /// 以太坊用的是 Merkle Patricia Tree  https://blog.csdn.net/tianlongtc/article/details/80418923
     ///   
-    fn merkle_root(mut vec_hash: Vec<[u8; 32]>) -> [u8; 32] {
+    pub fn merkle_root(vec_hash: Vec<[u8; 32]>, hasher: Hasher) -> [u8; 32] {
+        // 没有数据时 root 为 0
+        Self::merkle_tree(vec_hash, hasher).pop().unwrap_or([0; 32])
+    }
+
+    /// 两个子节点合并成父节点：hash(left || right)
+    fn merkle_merge(left: &[u8; 32], right: &[u8; 32], hasher: Hasher) -> [u8; 32] {
+        let mut data = [0u8; 64];
+        data[..32].copy_from_slice(left);
+        data[32..].copy_from_slice(right);
+        hasher.hash(&data)
+    }
+
+    /// 每一层依次排列：[叶子层, 第二层, ..., root]
+    fn merkle_tree(mut vec_hash: Vec<[u8; 32]>, hasher: Hasher) -> Vec<[u8; 32]> {
         let mut size = vec_hash.len();
-        if size == 0 {
-            return [0; 32];
-        }
 
         let mut j = 0usize;
         while size > 1 {
@@ -61,22 +113,60 @@ impl Block {
                 if i2 == size {
                     i2 = i1;
                 }
-                let merge = (vec_hash[i1 + j], vec_hash[i2 + j]);
-                let se = coder::serialize(&merge);
-                let mut hash = coder::get_hash(&se);
+                let hash = Self::merkle_merge(&vec_hash[i1 + j], &vec_hash[i2 + j], hasher);
                 // 为了之后 j += size;
                 vec_hash.push(hash);
                 i1 += 2;
             }
 
             j += size;
-            size = (size + 1) / 2;
+            size = size.div_ceil(2);
         }
 
-        match vec_hash.pop() {
-            Some(root_hash) => return root_hash,
-            None => panic!("vec_hash is empty!"),
+        vec_hash
+    }
+
+    /// tx_hash 的 merkle 证明：从叶子到 root 每一层的兄弟节点
+    /// SPV 钱包只保存区块头，用 verify_merkle_proof 验证交易在区块中
+    pub fn merkle_proof(&self, tx_hash: &[u8; 32], hasher: Hasher) -> Option<MerkleProof> {
+        let index = self
+            .transactions
+            .iter()
+            .position(|tx| tx.hash == *tx_hash)?;
+        let vec_hash = Self::merkle_leaves(&self.transactions, hasher);
+        let mut size = vec_hash.len();
+        let tree = Self::merkle_tree(vec_hash, hasher);
+
+        let mut siblings = Vec::new();
+        let mut i = index;
+        let mut j = 0usize;
+        while size > 1 {
+            let mut sibling = i ^ 1;
+            // 如果是奇数个数，最后一个自己与自己配对。
+            if sibling == size {
+                sibling = i;
+            }
+            siblings.push(tree[j + sibling]);
+
+            j += size;
+            size = size.div_ceil(2);
+            i /= 2;
         }
+
+        Some(MerkleProof {
+            index: index as u64,
+            siblings,
+        })
+    }
+
+    /// 叶子是交易的 witness_hash ，包括签名
+    fn merkle_leaves(vec_tx: &[Transaction], hasher: Hasher) -> Vec<[u8; 32]> {
+        vec_tx.iter().map(|tx| tx.witness_hash(hasher)).collect()
+    }
+
+    /// 交易列表的 merkle root ，即 BlockHeader.tx_hash
+    pub fn tx_merkle_root(vec_tx: &[Transaction], hasher: Hasher) -> [u8; 32] {
+        Self::merkle_root(Self::merkle_leaves(vec_tx, hasher), hasher)
     }
 
     pub fn new(
@@ -84,14 +174,13 @@ impl Block {
         pre_hash: [u8; 32],
         bits: u32,
         height: u64,
+        hasher: Hasher,
     ) -> Block {
-        let mut vec_hash = vec_tx.iter().map(|tx| tx.hash).collect::<Vec<[u8; 32]>>();
-
         Block {
             header: BlockHeader {
                 height,
                 time: Utc::now().timestamp(),
-                tx_hash: Self::merkle_root(vec_hash),
+                tx_hash: Self::tx_merkle_root(&vec_tx, hasher),
                 pre_hash,
                 bits,
                 nonce: 0,
@@ -102,3 +191,45 @@ impl Block {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_merkle_proof, Block};
+    use crate::core::transaction::Transaction;
+    use crate::utils::coder::Hasher;
+    use crate::utils::keypair::KeyPair;
+
+    #[test]
+    fn merkle_proof_works() {
+        let h = Hasher::default();
+        for n in 1..10u8 {
+            let txs: Vec<Transaction> = (0..n)
+                .map(|i| Transaction::new_coinbase([i; 32], i as u64, b"", h))
+                .collect();
+            let b = Block::new(txs, [0; 32], 0x2100FFFF, 1, h);
+
+            for tx in &b.transactions {
+                let proof = b.merkle_proof(&tx.hash, h).unwrap();
+                let leaf = tx.witness_hash(h);
+                assert!(verify_merkle_proof(&leaf, &proof, &b.header.tx_hash, h));
+
+                assert!(!verify_merkle_proof(&[9; 32], &proof, &b.header.tx_hash, h));
+                if let Some(sibling) = proof.siblings.first() {
+                    let mut bad = proof.clone();
+                    bad.siblings[0] = [sibling[0] ^ 1; 32];
+                    assert!(!verify_merkle_proof(&leaf, &bad, &b.header.tx_hash, h));
+                }
+            }
+        }
+        let b = Block::new(vec![], [0; 32], 0x2100FFFF, 1, h);
+        assert_eq!(b.merkle_proof(&[0; 32], h), None);
+
+        // 替换签名不改变 tx.hash ，但改变 merkle root
+        let keypair = KeyPair::generate();
+        let mut tx = Transaction::new(keypair.address(), [3; 32], 3, 1, 1, h);
+        tx.sign(&keypair).unwrap();
+        let root = Block::tx_merkle_root(&[tx.clone()], h);
+        tx.sign[0] ^= 1;
+        assert_ne!(Block::tx_merkle_root(&[tx], h), root);
+    }
+}
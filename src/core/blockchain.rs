@@ -1,124 +1,1174 @@
-use crate::core::bcdb::BlockChainDb;
-use crate::core::block::Block;
+use crate::core::account::Account;
+use crate::core::bcdb::{BlockChainDb, DB_VERSION};
+use crate::core::block::{Block, BlockHeader};
+use crate::core::config::ChainConfig;
+use crate::core::migrate;
 use crate::core::pow::ProofOfWork;
+use crate::core::state::{ImmatureFunds, PendingState, StateDb};
+use crate::core::storage::{Storage, WriteBatch};
 use crate::core::transaction::Transaction;
+use crate::core::trie::EMPTY_ROOT;
+use crate::core::validation::{self, BlockError};
+use crate::core::wire;
+use crate::error::{Error, Result};
 use crate::utils::coder;
-use crate::utils::key::MyKey;
-use crate::utils::key::U256;
-use leveldb::database::Database;
-use std::collections::HashMap;
-use std::sync::Mutex;
+use crate::utils::key::{Column, DbKey, U256};
+use crate::utils::lru::LruCache;
+use chrono::Utc;
+use std::sync::{Arc, Mutex, MutexGuard};
 
 pub struct BlockChain {
-    /// 仅仅用来 print
-    block_index: Mutex<HashMap<[u8; 32], Block>>,
-    blocks_db: Box<Database<MyKey>>,
-    /// genesis块 hash 程序里写死
+    /// 最近访问的区块，未命中时从数据库读取
+    block_cache: Mutex<LruCache<[u8; 32], Block>>,
+    /// 最近访问的区块头和累计工作量，同步和难度调整时频繁读取
+    header_cache: Mutex<LruCache<[u8; 32], (U256, BlockHeader)>>,
+    /// 区块、tail 、索引和 trie 节点
+    db: Arc<dyn Storage>,
+    /// 提交区块时等待数据落盘
+    sync: bool,
+    /// 当前 tail 的世界状态
+    state: StateDb,
+    /// 回滚的区块中、新链上没有的交易，需要重新打包
+    orphan_txs: Vec<Transaction>,
+    config: ChainConfig,
+    /// genesis块 hash 由 ChainConfig 决定
     pub genesis_hash: [u8; 32],
     pub curr_hash: [u8; 32],
     pub curr_bits: u32,
     /// 链长度
     pub curr_height: u64,
+    /// tail 的累计工作量
+    pub curr_work: U256,
 }
 
-// const DIFFICULTY_1_TARGET: u32 = 0x1d00ffff;
-/// 为了 pow 快速计算，暂时用这个数值
-const DIFFICULTY_1_TARGET: u32 = 0x2100FFFF;
+/// 缓存的区块数量
+const BLOCK_CACHE_SIZE: usize = 1024;
+/// 缓存的区块头数量，区块头很小，可以比区块多
+const HEADER_CACHE_SIZE: usize = 8192;
+/// 没有 recover 标记时，打开链时检查的 tail 之前的区块数
+const RECOVER_DEPTH: usize = 6;
 
 impl BlockChain {
-    fn write_block(db: &mut Database<MyKey>, b: &Block) {
-        let k = MyKey {
-            val: U256::from(b.hash),
-        };
+    fn write_block(batch: &mut WriteBatch, b: &Block) {
         let v = coder::serialize(&b);
+        BlockChainDb::write_db(batch, DbKey::new(Column::Block, &b.hash), &v);
+    }
 
-        BlockChainDb::write_db(db, k, &v);
+    fn tail_key() -> DbKey {
+        DbKey::meta("tail")
     }
 
     /// k -> tail, v -> b.hash
     /// write the end block hash to database
-    fn write_tail(db: &mut Database<MyKey>, b: &Block) {
-        let k = MyKey {
-            val: U256::from("tail".as_bytes()),
-        };
+    fn write_tail(batch: &mut WriteBatch, b: &Block) {
         let v = coder::serialize(&b.hash);
-        BlockChainDb::write_db(db, k, &v);
+        BlockChainDb::write_db(batch, Self::tail_key(), &v);
+    }
+
+    fn read_tail(db: &dyn Storage) -> Result<Option<[u8; 32]>> {
+        BlockChainDb::read_db(db, Self::tail_key())?
+            .map(|v| coder::deserialize(&v))
+            .transpose()
+    }
+
+    fn header_key(hash: &[u8; 32]) -> DbKey {
+        DbKey::new(Column::Header, hash)
+    }
+
+    /// 累计工作量和区块头写在一起
+    fn write_work(batch: &mut WriteBatch, b: &Block, work: U256) {
+        Self::write_header_entry(batch, &b.hash, &b.header, work);
+    }
+
+    /// 先于区块体下载的区块头也写在这里
+    fn write_header_entry(
+        batch: &mut WriteBatch,
+        hash: &[u8; 32],
+        header: &BlockHeader,
+        work: U256,
+    ) {
+        let mut v = vec![0u8; 32];
+        work.to_big_endian(&mut v);
+        v.extend_from_slice(&wire::encode_header(header));
+        BlockChainDb::write_db(batch, Self::header_key(hash), &v);
+    }
+
+    fn best_header_key() -> DbKey {
+        DbKey::meta("best_header")
+    }
+
+    /// 区块头和累计工作量写入后不再改变，可以缓存
+    fn read_header_entry(&self, hash: &[u8; 32]) -> Result<Option<(U256, BlockHeader)>> {
+        if let Some(entry) = self.lock_header_cache().get(hash) {
+            return Ok(Some(entry));
+        }
+
+        let v = match BlockChainDb::read_db(self.db.as_ref(), Self::header_key(hash))? {
+            Some(v) if v.len() > 32 => v,
+            Some(_) => return Err(Error::Codec(format!("bad header entry {:?}", hash))),
+            None => return Ok(None),
+        };
+        let entry = (
+            U256::from_big_endian(&v[..32]),
+            wire::decode_header(&v[32..])?,
+        );
+        self.lock_header_cache().put(*hash, entry.clone());
+        Ok(Some(entry))
+    }
+
+    /// 从 genesis 到 hash 区块的累计工作量
+    pub fn get_work(&self, hash: &[u8; 32]) -> Result<Option<U256>> {
+        Ok(self.read_header_entry(hash)?.map(|(work, _)| work))
+    }
+
+    /// 只读区块头，不需要读取整个区块
+    pub fn get_header(&self, hash: &[u8; 32]) -> Result<Option<BlockHeader>> {
+        Ok(self.read_header_entry(hash)?.map(|(_, header)| header))
+    }
+
+    /// 已经下载的区块头中累计工作量最大的一个，不超过 tail 时就是 tail
+    pub fn best_header(&self) -> Result<([u8; 32], BlockHeader)> {
+        if let Some(v) = BlockChainDb::read_db(self.db.as_ref(), Self::best_header_key())? {
+            let hash: [u8; 32] = coder::deserialize(&v)?;
+            if let Some((work, header)) = self.read_header_entry(&hash)? {
+                if work > self.curr_work {
+                    return Ok((hash, header));
+                }
+            }
+        }
+        let tail = self
+            .get_header(&self.curr_hash)?
+            .ok_or_else(|| Error::Storage(format!("header missing: {:?}", self.curr_hash)))?;
+        Ok((self.curr_hash, tail))
+    }
+
+    /// 先于区块体下载的连续区块头，第一个的父区块头必须已知
+    ///
+    /// 只做不需要交易和状态的检查：连接、高度、pow 、难度调整和时间戳，
+    /// 交易和状态在区块体通过 input_block 加入时检查。
+    /// 每个区块头单独写入，中途出错时之前的区块头仍然保留。返回新的区块头数量。
+    pub fn input_headers(&self, headers: &[BlockHeader]) -> Result<usize> {
+        let first = match headers.first() {
+            Some(header) => header,
+            None => return Ok(0),
+        };
+        let (mut parent_work, mut parent) = self
+            .read_header_entry(&first.pre_hash)?
+            .ok_or(BlockError::UnknownParent)?;
+        let mut parent_hash = first.pre_hash;
+        let (best_hash, _) = self.best_header()?;
+        let mut best_work = self.get_work(&best_hash)?.unwrap_or(self.curr_work);
+        let now = Utc::now().timestamp();
+        let mut added = 0;
+
+        for header in headers {
+            if header.pre_hash != parent_hash {
+                return Err(BlockError::UnknownParent.into());
+            }
+            let hash = header.hash(self.config.hasher);
+            if !ProofOfWork::new(header.bits).check(&hash) {
+                return Err(BlockError::HighHash.into());
+            }
+            validation::check_context(
+                header,
+                &parent,
+                self.next_bits_after(&parent)?,
+                self.median_time_past(&parent)?,
+                now,
+            )?;
+            let work = parent_work + ProofOfWork::work(header.bits);
+
+            if self.get_work(&hash)?.is_none() {
+                let mut batch = WriteBatch::new();
+                Self::write_header_entry(&mut batch, &hash, header, work);
+                if work > best_work {
+                    best_work = work;
+                    BlockChainDb::write_db(
+                        &mut batch,
+                        Self::best_header_key(),
+                        &coder::serialize(&hash),
+                    );
+                }
+                self.db.write(batch)?;
+                added += 1;
+            }
+
+            parent = header.clone();
+            parent_hash = hash;
+            parent_work = work;
+        }
+
+        Ok(added)
+    }
+
+    /// 区块体是否已经保存，不解码区块
+    pub fn has_block(&self, hash: &[u8; 32]) -> Result<bool> {
+        let k = DbKey::new(Column::Block, hash);
+        Ok(BlockChainDb::read_db(self.db.as_ref(), k)?.is_some())
+    }
+
+    /// 从 best_header 往回到第一个有区块体的祖先，缺少区块体的 (hash, height) ，按高度从低到高
+    pub fn missing_blocks(&self) -> Result<Vec<([u8; 32], u64)>> {
+        let (mut hash, mut header) = self.best_header()?;
+        let mut missing = Vec::new();
+        while !self.has_block(&hash)? {
+            missing.push((hash, header.height));
+            hash = header.pre_hash;
+            header = self
+                .get_header(&hash)?
+                .ok_or_else(|| Error::Storage(format!("header missing: {:?}", hash)))?;
+        }
+        missing.reverse();
+        Ok(missing)
+    }
+
+    /// 请求区块头时用来找到共同祖先：从 best_header 往回，前 10 个连续，之后间隔加倍，最后是 genesis
+    pub fn locator(&self) -> Result<Vec<[u8; 32]>> {
+        let (mut hash, mut header) = self.best_header()?;
+        let mut locator = Vec::new();
+        let mut step = 1;
+        loop {
+            locator.push(hash);
+            if header.height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            let height = header.height.saturating_sub(step);
+            // 主链上直接用高度索引，只有区块头的部分沿 pre_hash 往回
+            if BlockChainDb::read_height(self.db.as_ref(), header.height)? == Some(hash) {
+                hash = BlockChainDb::read_height(self.db.as_ref(), height)?
+                    .ok_or_else(|| Error::Storage(format!("height missing: {}", height)))?;
+                header = self
+                    .get_header(&hash)?
+                    .ok_or_else(|| Error::Storage(format!("header missing: {:?}", hash)))?;
+            } else {
+                while header.height > height {
+                    hash = header.pre_hash;
+                    header = self
+                        .get_header(&hash)?
+                        .ok_or_else(|| Error::Storage(format!("header missing: {:?}", hash)))?;
+                }
+            }
+        }
+        Ok(locator)
+    }
+
+    /// locator 中第一个在主链上的区块之后的主链区块头，最多 max 个，都不在主链上时从 genesis 之后开始
+    pub fn headers_after(&self, locator: &[[u8; 32]], max: usize) -> Result<Vec<BlockHeader>> {
+        let db = self.db.as_ref();
+        let mut height = 0;
+        for hash in locator {
+            if let Some(header) = self.get_header(hash)? {
+                if BlockChainDb::read_height(db, header.height)? == Some(*hash) {
+                    height = header.height;
+                    break;
+                }
+            }
+        }
+
+        let mut headers = Vec::new();
+        while headers.len() < max {
+            height += 1;
+            let hash = match BlockChainDb::read_height(db, height)? {
+                Some(hash) => hash,
+                None => break,
+            };
+            let header = self
+                .get_header(&hash)?
+                .ok_or_else(|| Error::Storage(format!("header missing: {:?}", hash)))?;
+            headers.push(header);
+        }
+        Ok(headers)
+    }
+
+    /// 缓存只是数据库的副本，持有锁的线程 panic 后仍然可以继续使用
+    fn cache(&self) -> MutexGuard<'_, LruCache<[u8; 32], Block>> {
+        self.block_cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_header_cache(&self) -> MutexGuard<'_, LruCache<[u8; 32], (U256, BlockHeader)>> {
+        self.header_cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn get_block(&self, hash: &[u8; 32]) -> Result<Option<Block>> {
+        if let Some(b) = self.cache().get(hash) {
+            return Ok(Some(b));
+        }
+
+        let k = DbKey::new(Column::Block, hash);
+        let b: Block = match BlockChainDb::read_db(self.db.as_ref(), k)? {
+            Some(v) => coder::deserialize(&v)?,
+            None => return Ok(None),
+        };
+        self.cache().put(b.hash, b.clone());
+
+        Ok(Some(b))
+    }
+
+    /// 应该存在的区块，例如主链上的区块，不存在说明数据库不完整
+    fn expect_block(&self, hash: &[u8; 32]) -> Result<Block> {
+        self.get_block(hash)?
+            .ok_or_else(|| Error::Storage(format!("block missing: {:?}", hash)))
+    }
+
+    /// 主链上 height 高度的区块
+    pub fn get_block_by_height(&self, height: u64) -> Result<Option<Block>> {
+        match BlockChainDb::read_height(self.db.as_ref(), height)? {
+            Some(hash) => self.get_block(&hash),
+            None => Ok(None),
+        }
+    }
+
+    /// 主链上包含交易的区块 hash 和交易在区块中的位置
+    pub fn get_tx_location(&self, tx_hash: &[u8; 32]) -> Result<Option<([u8; 32], u64)>> {
+        let (hash, pos) = match BlockChainDb::read_tx_index(self.db.as_ref(), tx_hash)? {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+        // 残留的索引指向已经不在主链上的区块
+        let b = match self.get_block(&hash)? {
+            Some(b) => b,
+            None => return Ok(None),
+        };
+        if BlockChainDb::read_height(self.db.as_ref(), b.header.height)? != Some(hash) {
+            return Ok(None);
+        }
+        Ok(Some((hash, pos)))
+    }
+
+    /// 主链上的交易
+    pub fn get_transaction(&self, tx_hash: &[u8; 32]) -> Result<Option<Transaction>> {
+        let (hash, pos) = match self.get_tx_location(tx_hash)? {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+        let b = self.expect_block(&hash)?;
+        Ok(b.transactions.get(pos as usize).cloned())
+    }
+
+    /// b 加入主链时写入 height 和交易索引
+    fn write_indexes(batch: &mut WriteBatch, b: &Block) {
+        BlockChainDb::write_height(batch, b.header.height, &b.hash);
+        for (i, tx) in b.transactions.iter().enumerate() {
+            BlockChainDb::write_tx_index(batch, &tx.hash, &b.hash, i as u64);
+        }
+    }
+
+    /// b 离开主链时删除 height 和交易索引
+    fn delete_indexes(batch: &mut WriteBatch, b: &Block) {
+        BlockChainDb::delete_height(batch, b.header.height);
+        for tx in b.transactions.iter() {
+            BlockChainDb::delete_tx_index(batch, &tx.hash);
+        }
+    }
+
+    /// b 的 height 和交易索引是否完整
+    fn has_indexes(&self, b: &Block) -> Result<bool> {
+        let db = self.db.as_ref();
+        if BlockChainDb::read_height(db, b.header.height)? != Some(b.hash) {
+            return Ok(false);
+        }
+        for (i, tx) in b.transactions.iter().enumerate() {
+            if BlockChainDb::read_tx_index(db, &tx.hash)? != Some((b.hash, i as u64)) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// 父区块及之前最多 MEDIAN_TIME_SPAN 个区块时间戳的中位数
+    /// 只读取区块头，还没有下载区块体的区块头也可以使用
+    fn median_time_past(&self, parent: &BlockHeader) -> Result<i64> {
+        let mut times = Vec::new();
+        let mut curr = Some(parent.clone());
+        while let Some(header) = curr {
+            times.push(header.time);
+            if times.len() == validation::MEDIAN_TIME_SPAN || header.height == 0 {
+                break;
+            }
+            curr = self.get_header(&header.pre_hash)?;
+        }
+
+        Ok(validation::median_time(times))
+    }
+
+    /// parent 往前第 n 个祖先的区块头
+    fn get_ancestor(&self, parent: &BlockHeader, n: u64) -> Result<Option<BlockHeader>> {
+        let mut header = parent.clone();
+        for _ in 0..n {
+            header = match self.get_header(&header.pre_hash)? {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(header))
+    }
+
+    /// parent 之后下一个区块的 bits
+    /// 高度是 retarget_interval 的整数倍时，用前 retarget_interval 个区块的时间调整难度
+    pub fn next_bits_after(&self, parent: &BlockHeader) -> Result<u32> {
+        let interval = self.config.retarget_interval;
+        let height = parent.height + 1;
+        if !height.is_multiple_of(interval) {
+            return Ok(parent.bits);
+        }
+
+        // 与 bitcoin 一样，实际只统计了 retarget_interval - 1 个间隔
+        let first = match self.get_ancestor(parent, interval - 1)? {
+            Some(header) => header,
+            None => return Ok(parent.bits),
+        };
+        let actual_timespan = parent.time - first.time;
+
+        Ok(ProofOfWork::retarget(
+            parent.bits,
+            actual_timespan,
+            interval,
+            self.config.initial_bits,
+        ))
+    }
+
+    /// tail 之后下一个区块的 bits
+    pub fn next_bits(&self) -> Result<u32> {
+        match self.get_block(&self.curr_hash)? {
+            Some(tail) => self.next_bits_after(&tail.header),
+            None => Ok(self.curr_bits),
+        }
+    }
+
+    /// 验证通过才写入数据库
+    pub fn validate_block(&self, b: &Block) -> Result<()> {
+        if self.get_block(&b.hash)?.is_some() {
+            return Err(BlockError::AlreadyKnown.into());
+        }
+        validation::check_block(b, self.config.max_block_size, self.config.hasher)?;
+
+        let parent = self
+            .get_block(&b.header.pre_hash)?
+            .ok_or(BlockError::UnknownParent)?;
+        let median_time = self.median_time_past(&parent.header)?;
+        validation::check_context(
+            &b.header,
+            &parent.header,
+            self.next_bits_after(&parent.header)?,
+            median_time,
+            Utc::now().timestamp(),
+        )?;
+        validation::check_coinbase(b, self.config.subsidy(b.header.height))?;
+
+        Ok(())
+    }
+
+    /// parent 之后的区块不能花费的 coinbase
+    /// h 高度的 coinbase 要到 h + coinbase_maturity 高度才能花费
+    fn immature_after(&self, parent: &Block) -> Result<ImmatureFunds> {
+        let height = parent.header.height + 1;
+        let mut immature = ImmatureFunds::new();
+        let mut b = parent.clone();
+        while b.header.height > 0 && b.header.height + self.config.coinbase_maturity > height {
+            if let Some(coinbase) = b.transactions.first() {
+                let locked = immature.entry(coinbase.to).or_insert(0);
+                *locked = locked.saturating_add(coinbase.amount);
+            }
+            b = self.expect_block(&b.header.pre_hash)?;
+        }
+        Ok(immature)
+    }
+
+    /// tail 之后的区块中 address 不能花费的 coinbase 金额
+    pub fn immature_balance(&self, address: &[u8; 32]) -> Result<u64> {
+        let tail = self.expect_block(&self.curr_hash)?;
+        let immature = self.immature_after(&tail)?;
+        Ok(immature.get(address).copied().unwrap_or(0))
     }
 
-    pub fn input_block(&mut self, b: Block) -> Result<(), String> {
-        Self::write_block(&mut self.blocks_db, &b);
-        // write tail
-        if b.header.height > self.curr_height {
-            Self::write_tail(&mut self.blocks_db, &b);
+    /// 分叉上的区块同样完整验证并保存，累计工作量超过 tail 时切换到新链
+    /// 区块、累计工作量、trie 节点，以及 tail 和索引的变化在同一个 batch 中写入
+    /// 写入失败时内存中的 tail 不变
+    pub fn input_block(&mut self, b: Block) -> Result<()> {
+        self.validate_block(&b)?;
+
+        let parent = self
+            .get_block(&b.header.pre_hash)?
+            .ok_or(BlockError::UnknownParent)?;
+        let mut batch = WriteBatch::new();
+        let immature = self.immature_after(&parent)?;
+        self.state
+            .apply_block(&parent.header.state_root, &b, &immature, &mut batch)?;
+        let parent_work = self
+            .get_work(&parent.hash)?
+            .ok_or_else(|| Error::Storage(format!("work missing: {:?}", parent.hash)))?;
+        let work = parent_work + ProofOfWork::work(b.header.bits);
+
+        Self::write_block(&mut batch, &b);
+        Self::write_work(&mut batch, &b, work);
+
+        // 读取都在写入之前完成，写入失败时内存中的 tail 不变
+        let reorg = if work > self.curr_work {
+            let reorg = self.plan_reorg(&b, work)?;
+            Self::write_reorg(&reorg, &mut batch);
+            Some(reorg)
+        } else {
+            None
+        };
+
+        batch.set_sync(self.sync);
+        self.db.write(batch)?;
+
+        if let Some(reorg) = reorg {
+            self.apply_reorg(reorg);
+        }
+        self.cache().put(b.hash, b);
+
+        Ok(())
+    }
+
+    /// 回朔：从 tail 回滚到分叉点，再沿新链接到 new_tip
+    /// new_tip 还没有写入数据库，它的累计工作量由调用者传入
+    fn plan_reorg(&self, new_tip: &Block, new_work: U256) -> Result<Reorg> {
+        let mut old = self.expect_block(&self.curr_hash)?;
+        let mut new = new_tip.clone();
+        let mut disconnect = Vec::new();
+        let mut connect = Vec::new();
+
+        // 找到分叉点
+        while old.hash != new.hash {
+            if old.header.height >= new.header.height {
+                let parent = self.expect_block(&old.header.pre_hash)?;
+                disconnect.push(old);
+                old = parent;
+            } else {
+                let parent = self.expect_block(&new.header.pre_hash)?;
+                let work = if new.hash == new_tip.hash {
+                    new_work
+                } else {
+                    self.get_work(&new.hash)?
+                        .ok_or_else(|| Error::Storage(format!("work missing: {:?}", new.hash)))?
+                };
+                connect.push((new, work));
+                new = parent;
+            }
+        }
+        connect.reverse();
+
+        Ok(Reorg {
+            disconnect,
+            connect,
+        })
+    }
+
+    /// 索引和 tail 的变化放进 batch
+    fn write_reorg(reorg: &Reorg, batch: &mut WriteBatch) {
+        for b in &reorg.disconnect {
+            Self::delete_indexes(batch, b);
+        }
+        for (b, _) in &reorg.connect {
+            Self::write_indexes(batch, b);
+        }
+        if let Some((tip, _)) = reorg.connect.last() {
+            Self::write_tail(batch, tip);
+        }
+    }
+
+    /// batch 写入成功后更新内存中的 tail 和回滚的交易
+    fn apply_reorg(&mut self, reorg: Reorg) {
+        if !reorg.disconnect.is_empty() {
+            println!(
+                "reorganize: disconnect {} blocks, connect {} blocks",
+                reorg.disconnect.len(),
+                reorg.connect.len()
+            );
+        }
+        for b in &reorg.disconnect {
+            let txs = b.transactions.iter().filter(|tx| !tx.is_coinbase());
+            self.orphan_txs.extend(txs.cloned());
+        }
+        for (b, work) in reorg.connect {
+            let hashes: Vec<[u8; 32]> = b.transactions.iter().map(|tx| tx.hash).collect();
+            self.orphan_txs.retain(|tx| !hashes.contains(&tx.hash));
+
+            self.state.set_root(b.header.state_root);
             self.curr_hash = b.hash;
             self.curr_bits = b.header.bits;
             self.curr_height = b.header.height;
-
-            // 再判断是否需要回朔
+            self.curr_work = work;
         }
+    }
 
-        // TODO 无限添加 内存爆炸
-        Self::update_map(&mut self.block_index, b.clone());
+    /// 回滚的区块中、新链上没有的交易
+    pub fn take_orphan_txs(&mut self) -> Vec<Transaction> {
+        std::mem::take(&mut self.orphan_txs)
+    }
 
-        Ok(())
+    /// 当前 tail 的账户状态
+    pub fn get_account(&self, address: &[u8; 32]) -> Result<Account> {
+        self.state.get_account(address)
     }
 
-    fn get_genesis_block() -> Block {
-        let tx = Transaction::new([0; 32], [0; 32], 0, 0, 0, "This is genesis".to_string());
-        let mut b = Block::new(vec![tx], [0; 32], DIFFICULTY_1_TARGET, 0);
-        let data = ProofOfWork::block_header_se(&mut b, 0);
-        b.hash = coder::get_hash(&data);
+    /// hash 区块时刻 address 的账户证明，用区块头的 state_root 验证
+    /// 见 state::verify_account_proof
+    pub fn prove_account(
+        &self,
+        address: &[u8; 32],
+        hash: &[u8; 32],
+    ) -> Result<Option<Vec<Vec<u8>>>> {
+        match self.get_block(hash)? {
+            Some(b) => Ok(Some(self.state.prove(&b.header.state_root, address)?)),
+            None => Ok(None),
+        }
+    }
 
-        b
+    /// 在 tail 之后出块时逐笔执行交易
+    pub fn pending_state(&self) -> Result<PendingState<'_>> {
+        let tail = self.expect_block(&self.curr_hash)?;
+        let immature = self.immature_after(&tail)?;
+        Ok(PendingState::new(&self.state, self.state.root(), immature))
     }
 
-    fn update_map(map: &mut Mutex<HashMap<[u8; 32], Block>>, block: Block) {
-        let mut map = map.lock().unwrap();
-        map.insert(block.hash, block);
+    /// genesis 的内容固定，同一个网络所有节点的 genesis_hash 才一致
+    /// genesis 不需要 pow ，nonce 存放 network_id
+    pub(crate) fn get_genesis_block(config: &ChainConfig) -> Block {
+        let data = config.genesis_message.as_bytes();
+        let tx = Transaction::new_coinbase([0; 32], 0, data, config.hasher);
+        let mut b = Block::new(vec![tx], [0; 32], config.initial_bits, 0, config.hasher);
+        b.header.time = config.genesis_time;
+        b.header.nonce = config.network_id;
+        b.hash = b.header.hash(config.hasher);
+
+        b
     }
 
-    pub fn new_blockchain() -> BlockChain {
-        let mut db = BlockChainDb::new_db("blockchain_db");
-        let genesis = Self::get_genesis_block();
-        Self::write_block(&mut db, &genesis);
-        Self::write_tail(&mut db, &genesis);
+    /// 打开 config.data_dir 下的数据库
+    pub fn open(config: ChainConfig) -> Result<BlockChain> {
+        let db = BlockChainDb::new_db(&config.data_dir)?;
+        Self::with_storage(db, config)
+    }
 
-        let mut map = Mutex::new(HashMap::new());
-        Self::update_map(&mut map, genesis.clone());
-        let genesis_hash = genesis.hash;
+    /// 数据库为空时写入 genesis ，否则从 tail 恢复
+    pub fn with_storage(db: Arc<dyn Storage>, config: ChainConfig) -> Result<BlockChain> {
+        config.validate()?;
+        let genesis = Self::get_genesis_block(&config);
+        if Self::read_tail(db.as_ref())?.is_none() {
+            if migrate::is_legacy(db.as_ref())? {
+                return Err(Error::Storage(
+                    "database uses the old key layout, run the migrate tool first".to_string(),
+                ));
+            }
+            let mut batch = WriteBatch::new();
+            Self::write_block(&mut batch, &genesis);
+            Self::write_tail(&mut batch, &genesis);
+            Self::write_work(&mut batch, &genesis, ProofOfWork::work(genesis.header.bits));
+            Self::write_indexes(&mut batch, &genesis);
+            BlockChainDb::write_version(&mut batch);
+            db.write(batch)?;
+        }
+        match BlockChainDb::read_version(db.as_ref())? {
+            Some(DB_VERSION) => {}
+            v => {
+                return Err(Error::Storage(format!(
+                    "unsupported database version {:?}",
+                    v
+                )))
+            }
+        }
 
-        BlockChain {
-            block_index: map,
-            genesis_hash,
-            curr_bits: DIFFICULTY_1_TARGET,
-            blocks_db: Box::new(db),
-            curr_hash: genesis_hash,
+        let mut chain = BlockChain {
+            block_cache: Mutex::new(LruCache::new(BLOCK_CACHE_SIZE)),
+            header_cache: Mutex::new(LruCache::new(HEADER_CACHE_SIZE)),
+            genesis_hash: genesis.hash,
+            curr_bits: config.initial_bits,
+            state: StateDb::new(db.clone(), config.hasher),
+            db,
+            sync: config.sync,
+            orphan_txs: Vec::new(),
+            config,
+            curr_hash: genesis.hash,
             curr_height: 0,
+            curr_work: U256::zero(),
+        };
+        chain.load_tail()?;
+
+        Ok(chain)
+    }
+
+    pub fn config(&self) -> &ChainConfig {
+        &self.config
+    }
+
+    /// 提交区块时是否等待数据落盘，更安全但更慢
+    pub fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    /// 检查 genesis 是否一致并修复 tail 之前的区块，再恢复 curr_*
+    ///
+    /// 提交是原子的，只检查最后 RECOVER_DEPTH 个区块；有 recover 标记时从 genesis 开始检查
+    fn load_tail(&mut self) -> Result<()> {
+        let tail_hash = Self::read_tail(self.db.as_ref())?
+            .ok_or_else(|| Error::Storage("tail not found".to_string()))?;
+        let tail = self.expect_block(&tail_hash)?;
+        let full = BlockChainDb::needs_recover(self.db.as_ref())?;
+
+        let mut main_chain = Vec::new();
+        let mut b = tail.clone();
+        while b.header.height > 0 && (full || main_chain.len() < RECOVER_DEPTH) {
+            main_chain.push(b.hash);
+            b = self.expect_block(&b.header.pre_hash)?;
+        }
+        // b 是 genesis ，或者是检查的第一个区块的父区块
+        let (genesis, work, root) = if b.header.height == 0 {
+            main_chain.push(b.hash);
+            (Some(b.hash), U256::zero(), EMPTY_ROOT)
+        } else {
+            let work = self
+                .get_work(&b.hash)?
+                .ok_or_else(|| Error::Storage(format!("work missing: {:?}", b.hash)))?;
+            let genesis = BlockChainDb::read_height(self.db.as_ref(), 0)?;
+            (genesis, work, b.header.state_root)
+        };
+        if genesis != Some(self.genesis_hash) {
+            return Err(Error::Consensus(format!(
+                "genesis mismatch, expected {:?} found {:?}",
+                self.genesis_hash, genesis
+            )));
+        }
+        main_chain.reverse();
+
+        let work = self.recover(&main_chain, work, root)?;
+        if full {
+            let mut batch = WriteBatch::new();
+            BlockChainDb::delete_recover(&mut batch);
+            self.db.write(batch)?;
         }
+
+        self.state.set_root(tail.header.state_root);
+        self.curr_hash = tail.hash;
+        self.curr_bits = tail.header.bits;
+        self.curr_height = tail.header.height;
+        self.curr_work = work;
+        println!("load blockchain, height: {}", self.curr_height);
+
+        Ok(())
     }
 
-    pub fn print(&self) {
-        let mut hash = self.curr_hash;
-        let mut blocks: Vec<Block> = Vec::new();
+    /// 修复没有完整写入的提交（旧版本的区块、tail 、索引和状态分多次写入），返回 tail 的累计工作量
+    /// - 补上主链区块缺少的累计工作量、height 和交易索引
+    /// - 删除 tail 之上残留的 height 索引
+    /// - trie 节点缺失的区块，在父区块的状态上重新执行
+    ///
+    /// work 和 parent_root 是 main_chain 第一个区块的父区块的，从 genesis 开始时为 0 和 EMPTY_ROOT
+    fn recover(
+        &mut self,
+        main_chain: &[[u8; 32]],
+        mut work: U256,
+        mut parent_root: [u8; 32],
+    ) -> Result<U256> {
+        let mut repaired = 0;
 
-        let map = self.block_index.lock().unwrap();
-        loop {
-            if let Some(b) = map.get(&hash) {
-                hash = b.header.pre_hash;
-                blocks.push(b.clone());
-            } else {
-                panic!("found block error");
+        for hash in main_chain {
+            let b = self.expect_block(hash)?;
+            let mut batch = WriteBatch::new();
+
+            work += ProofOfWork::work(b.header.bits);
+            // 损坏的累计工作量直接覆盖
+            if !matches!(self.get_work(hash), Ok(Some(w)) if w == work) {
+                Self::write_work(&mut batch, &b, work);
+            }
+            if !self.has_indexes(&b)? {
+                Self::write_indexes(&mut batch, &b);
             }
+            if !self.state.has_root(&b.header.state_root)? {
+                let parent = self.expect_block(&b.header.pre_hash)?;
+                let immature = self.immature_after(&parent)?;
+                self.state
+                    .apply_block(&parent_root, &b, &immature, &mut batch)
+                    .map_err(|e| {
+                        Error::Consensus(format!("failed to recover state at {:?}: {}", hash, e))
+                    })?;
+            }
+            parent_root = b.header.state_root;
 
-            if hash == self.genesis_hash {
-                break;
+            // 下一个区块的状态可能依赖这个区块的 trie 节点，每个区块单独写入
+            if !batch.is_empty() {
+                repaired += 1;
+                self.db.write(batch)?;
             }
         }
 
-        blocks.reverse();
-        for b in blocks {
-            println!("--------------------------------------------------------------------------");
-            println!("{:?}\n", b);
+        let mut batch = WriteBatch::new();
+        let mut height = self
+            .expect_block(&main_chain[main_chain.len() - 1])?
+            .header
+            .height
+            + 1;
+        while BlockChainDb::read_height(self.db.as_ref(), height)?.is_some() {
+            BlockChainDb::delete_height(&mut batch, height);
+            height += 1;
+        }
+        if !batch.is_empty() {
+            repaired += 1;
+            self.db.write(batch)?;
+        }
+
+        if repaired > 0 {
+            println!("recover: repaired {} half-applied commits", repaired);
+        }
+        Ok(work)
+    }
+
+    /// 从 genesis 到 tail 打印主链
+    pub fn print(&self) -> Result<()> {
+        for height in 0..=self.curr_height {
+            match self.get_block_by_height(height)? {
+                Some(b) => {
+                    println!("--------------------------------------------------------------------------");
+                    println!("{:?}\n", b);
+                }
+                None => eprintln!("block at height {} not found", height),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// tail 的变化：先回滚 disconnect（从 tail 开始），再按顺序接上 connect
+/// connect 中是区块和它的累计工作量，最后一个是新的 tail
+struct Reorg {
+    disconnect: Vec<Block>,
+    connect: Vec<(Block, U256)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockChain;
+    use crate::core::account::Account;
+    use crate::core::bcdb::BlockChainDb;
+    use crate::core::block::Block;
+    use crate::core::config::ChainConfig;
+    use crate::core::pow::ProofOfWork;
+    use crate::core::storage::{MemoryStorage, Storage, WriteBatch};
+    use crate::core::transaction::Transaction;
+    use crate::core::validation::BlockError;
+    use crate::error::Error;
+    use crate::utils::coder::Hasher;
+    use crate::utils::key::{Column, DbKey};
+    use crate::utils::lru::LruCache;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    /// 在 parent 之后出块，parent 不一定是 tail
+    fn mine_on(
+        chain: &BlockChain,
+        parent: &Block,
+        miner: [u8; 32],
+        txs: Vec<Transaction>,
+    ) -> Block {
+        let fees: u64 = txs.iter().map(|tx| tx.fee).sum();
+        let reward = chain.config.subsidy(parent.header.height + 1) + fees;
+        mine_with_reward(chain, parent, miner, reward, txs)
+    }
+
+    /// coinbase 金额为 reward
+    fn mine_with_reward(
+        chain: &BlockChain,
+        parent: &Block,
+        miner: [u8; 32],
+        reward: u64,
+        txs: Vec<Transaction>,
+    ) -> Block {
+        let height = parent.header.height + 1;
+        let data = format!("coinbase {}", height);
+        let h = chain.config.hasher;
+        let mut vec_tx = vec![Transaction::new_coinbase(miner, reward, data.as_bytes(), h)];
+        vec_tx.extend(txs);
+        let root = parent.header.state_root;
+        let immature = chain.immature_after(parent).unwrap();
+        let changes = chain.state.execute(&root, &vec_tx, &immature).unwrap();
+
+        let bits = chain.next_bits_after(&parent.header).unwrap();
+        let mut b = Block::new(vec_tx, parent.hash, bits, parent.header.height + 1, h);
+        b.header.state_root = chain.state.root_with(&root, &changes).unwrap();
+        ProofOfWork::new(bits).run(&mut b, h, &AtomicBool::new(false));
+        b
+    }
+
+    #[test]
+    fn reorganize_works() {
+        let mut chain =
+            BlockChain::with_storage(Arc::new(MemoryStorage::new()), ChainConfig::default())
+                .unwrap();
+        let genesis = chain.get_block(&chain.genesis_hash).unwrap().unwrap();
+        let mut alice = Account::generate();
+        let tx = alice.send_to([3; 32], 0, 0, chain.config.hasher).unwrap();
+
+        let a1 = mine_on(&chain, &genesis, [1; 32], vec![tx.clone()]);
+        chain.input_block(a1.clone()).unwrap();
+        assert_eq!(chain.curr_hash, a1.hash);
+        assert_eq!(chain.get_account(&alice.address).unwrap().nonce, 1);
+
+        // 工作量相同，不切换
+        let b1 = mine_on(&chain, &genesis, [2; 32], vec![]);
+        chain.input_block(b1.clone()).unwrap();
+        assert_eq!(chain.curr_hash, a1.hash);
+
+        // 工作量更大，切换到 b 链，a1 的交易回滚
+        let b2 = mine_on(&chain, &b1, [2; 32], vec![]);
+        chain.input_block(b2.clone()).unwrap();
+        assert_eq!(chain.curr_hash, b2.hash);
+        assert_eq!(chain.curr_height, 2);
+        assert_eq!(chain.get_account(&alice.address).unwrap().nonce, 0);
+        let orphans = chain.take_orphan_txs();
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].hash, tx.hash);
+        assert_eq!(chain.get_block_by_height(1).unwrap().unwrap().hash, b1.hash);
+        assert!(chain.get_transaction(&tx.hash).unwrap().is_none());
+        assert!(chain
+            .get_tx_location(&a1.transactions[0].hash)
+            .unwrap()
+            .is_none());
+
+        // 再切换回 a 链
+        let a2 = mine_on(&chain, &a1, [1; 32], vec![]);
+        chain.input_block(a2.clone()).unwrap();
+        assert_eq!(chain.curr_hash, b2.hash);
+        let a3 = mine_on(&chain, &a2, [1; 32], vec![]);
+        chain.input_block(a3.clone()).unwrap();
+        assert_eq!(chain.curr_hash, a3.hash);
+        assert_eq!(chain.get_account(&alice.address).unwrap().nonce, 1);
+        assert!(chain.take_orphan_txs().is_empty());
+        assert_eq!(
+            chain.get_block_by_height(0).unwrap().unwrap().hash,
+            genesis.hash
+        );
+        assert_eq!(chain.get_block_by_height(2).unwrap().unwrap().hash, a2.hash);
+        assert!(chain.get_block_by_height(4).unwrap().is_none());
+        assert_eq!(chain.get_tx_location(&tx.hash).unwrap(), Some((a1.hash, 1)));
+        assert_eq!(
+            chain.get_transaction(&tx.hash).unwrap().unwrap().hash,
+            tx.hash
+        );
+        assert!(chain
+            .get_tx_location(&b2.transactions[0].hash)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn recover_works() {
+        let db: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let mut chain = BlockChain::with_storage(db.clone(), ChainConfig::default()).unwrap();
+        let genesis = chain.get_block(&chain.genesis_hash).unwrap().unwrap();
+        let mut alice = Account::generate();
+        let tx = alice.send_to([3; 32], 0, 0, chain.config.hasher).unwrap();
+
+        let b1 = mine_on(&chain, &genesis, [1; 32], vec![tx.clone()]);
+        chain.input_block(b1.clone()).unwrap();
+        let b2 = mine_on(&chain, &b1, [1; 32], vec![]);
+        chain.input_block(b2.clone()).unwrap();
+        let work = chain.curr_work;
+        drop(chain);
+
+        // 旧版本写到一半：区块和 tail 已经写入，累计工作量、索引和状态没有写入
+        // migrate 转换后留下 recover 标记
+        let mut batch = WriteBatch::new();
+        BlockChainDb::write_recover(&mut batch);
+        BlockChainDb::delete_db(&mut batch, BlockChain::header_key(&b2.hash));
+        BlockChainDb::delete_height(&mut batch, 2);
+        BlockChainDb::delete_tx_index(&mut batch, &tx.hash);
+        BlockChainDb::write_height(&mut batch, 3, &[9; 32]);
+        for root in [b1.header.state_root, b2.header.state_root] {
+            BlockChainDb::delete_db(&mut batch, DbKey::new(Column::State, &root));
+        }
+        db.write(batch).unwrap();
+
+        let chain = BlockChain::with_storage(db.clone(), ChainConfig::default()).unwrap();
+        assert!(!BlockChainDb::needs_recover(db.as_ref()).unwrap());
+        assert_eq!(chain.curr_hash, b2.hash);
+        assert_eq!(chain.curr_work, work);
+        assert_eq!(chain.get_work(&b2.hash).unwrap(), Some(work));
+        assert_eq!(chain.get_block_by_height(2).unwrap().unwrap().hash, b2.hash);
+        assert!(chain.get_block_by_height(3).unwrap().is_none());
+        assert_eq!(chain.get_tx_location(&tx.hash).unwrap(), Some((b1.hash, 1)));
+        assert_eq!(chain.get_account(&alice.address).unwrap().nonce, 1);
+        drop(chain);
+
+        // 没有 recover 标记时只检查 tail 附近的区块
+        let mut batch = WriteBatch::new();
+        BlockChainDb::delete_height(&mut batch, 2);
+        db.write(batch).unwrap();
+        let chain = BlockChain::with_storage(db, ChainConfig::default()).unwrap();
+        assert_eq!(chain.get_block_by_height(2).unwrap().unwrap().hash, b2.hash);
+    }
+
+    #[test]
+    fn coinbase_maturity_works() {
+        let config = ChainConfig {
+            coinbase_maturity: 2,
+            ..ChainConfig::default()
+        };
+        let mut chain = BlockChain::with_storage(Arc::new(MemoryStorage::new()), config).unwrap();
+        let genesis = chain.get_block(&chain.genesis_hash).unwrap().unwrap();
+        let mut alice = Account::generate();
+
+        // 超过出块奖励 + 手续费
+        let bad = mine_with_reward(&chain, &genesis, alice.address, 51, vec![]);
+        assert_eq!(
+            chain.input_block(bad),
+            Err(Error::Validation(BlockError::BadCoinbase {
+                max: 50,
+                found: 51
+            }))
+        );
+
+        let b1 = mine_on(&chain, &genesis, alice.address, vec![]);
+        chain.input_block(b1.clone()).unwrap();
+        assert_eq!(chain.get_account(&alice.address).unwrap().balance, 50);
+        assert_eq!(chain.immature_balance(&alice.address).unwrap(), 50);
+
+        // 高度 2 还不能花费高度 1 的 coinbase
+        alice.balance = 50;
+        let tx = alice.send_to([3; 32], 10, 1, chain.config.hasher).unwrap();
+        let mut pending = chain.pending_state().unwrap();
+        assert_eq!(
+            pending.add(&tx),
+            Err(Error::Validation(BlockError::ImmatureCoinbase(1)))
+        );
+
+        let b2 = mine_on(&chain, &b1, [1; 32], vec![]);
+        chain.input_block(b2.clone()).unwrap();
+        assert_eq!(chain.immature_balance(&alice.address).unwrap(), 0);
+        let b3 = mine_on(&chain, &b2, [1; 32], vec![tx]);
+        chain.input_block(b3.clone()).unwrap();
+        assert_eq!(chain.curr_hash, b3.hash);
+        assert_eq!(chain.get_account(&alice.address).unwrap().balance, 39);
+        assert_eq!(chain.get_account(&[1; 32]).unwrap().balance, 101);
+    }
+
+    #[test]
+    fn headers_first_works() {
+        let mut source =
+            BlockChain::with_storage(Arc::new(MemoryStorage::new()), ChainConfig::default())
+                .unwrap();
+        let mut blocks = vec![source.get_block(&source.genesis_hash).unwrap().unwrap()];
+        for _ in 0..3 {
+            let b = mine_on(&source, blocks.last().unwrap(), [1; 32], vec![]);
+            source.input_block(b.clone()).unwrap();
+            blocks.push(b);
+        }
+        let headers = source.headers_after(&[source.genesis_hash], 2000).unwrap();
+        assert_eq!(headers.len(), 3);
+        assert_eq!(
+            source.headers_after(&[blocks[2].hash], 2000).unwrap().len(),
+            1
+        );
+
+        let db: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let chain = BlockChain::with_storage(db.clone(), ChainConfig::default()).unwrap();
+        let mut bad = headers[1].clone();
+        bad.height = 5;
+        assert_eq!(
+            chain.input_headers(&[headers[0].clone(), bad]),
+            Err(Error::Validation(BlockError::BadHeight {
+                expected: 2,
+                found: 5
+            }))
+        );
+        assert_eq!(
+            chain.input_headers(&headers[2..]),
+            Err(Error::Validation(BlockError::UnknownParent))
+        );
+        // 出错之前的区块头已经写入
+        assert_eq!(chain.best_header().unwrap().0, blocks[1].hash);
+        assert_eq!(chain.input_headers(&headers).unwrap(), 2);
+        drop(chain);
+
+        // 重启后继续下载区块体
+        let mut chain = BlockChain::with_storage(db, ChainConfig::default()).unwrap();
+        assert_eq!(chain.curr_height, 0);
+        assert_eq!(chain.best_header().unwrap().0, blocks[3].hash);
+        let locator = chain.locator().unwrap();
+        assert_eq!(locator.first(), Some(&blocks[3].hash));
+        assert_eq!(locator.last(), Some(&chain.genesis_hash));
+        let missing = chain.missing_blocks().unwrap();
+        assert_eq!(
+            missing,
+            vec![
+                (blocks[1].hash, 1),
+                (blocks[2].hash, 2),
+                (blocks[3].hash, 3)
+            ]
+        );
+
+        for b in &blocks[1..] {
+            chain.input_block(b.clone()).unwrap();
+        }
+        assert_eq!(chain.curr_hash, blocks[3].hash);
+        assert_eq!(chain.best_header().unwrap().0, blocks[3].hash);
+        assert!(chain.missing_blocks().unwrap().is_empty());
+
+        // 区块头缓存有上限，淘汰后从数据库读取
+        *chain.header_cache.lock().unwrap() = LruCache::new(2);
+        for b in blocks.iter().chain(blocks.iter()) {
+            let header = chain.get_header(&b.hash).unwrap().unwrap();
+            assert_eq!(header.hash(chain.config.hasher), b.hash);
+            assert!(chain.header_cache.lock().unwrap().len() <= 2);
         }
+        assert_eq!(chain.header_cache.lock().unwrap().len(), 2);
+    }
+
+    /// genesis_message 写在 genesis 的交易中，不同的 genesis_message 得到不同的 genesis
+    #[test]
+    fn genesis_message_works() {
+        let open = |message: &str| {
+            let config = ChainConfig {
+                genesis_message: message.to_string(),
+                ..ChainConfig::default()
+            };
+            BlockChain::with_storage(Arc::new(MemoryStorage::new()), config).unwrap()
+        };
+        let a = open("This is genesis");
+        let b = open("This is another genesis");
+        assert_eq!(a.genesis_hash, open("This is genesis").genesis_hash);
+        assert_ne!(a.genesis_hash, b.genesis_hash);
+    }
+
+    /// hash 函数属于每条链，同一个进程中的两条链互不影响
+    #[test]
+    fn hasher_per_chain_works() {
+        let open = |hasher| {
+            let config = ChainConfig {
+                hasher,
+                ..ChainConfig::default()
+            };
+            BlockChain::with_storage(Arc::new(MemoryStorage::new()), config).unwrap()
+        };
+        let mut sha3 = open(Hasher::Sha3_256);
+        let mut blake = open(Hasher::Blake2b);
+        assert_ne!(sha3.genesis_hash, blake.genesis_hash);
+
+        for chain in [&mut sha3, &mut blake] {
+            let genesis = chain.expect_block(&chain.genesis_hash).unwrap();
+            let b = mine_on(chain, &genesis, [1; 32], vec![]);
+            assert_eq!(b.header.hash(chain.config.hasher), b.hash);
+            chain.input_block(b.clone()).unwrap();
+            assert_eq!(chain.curr_hash, b.hash);
+        }
+    }
+
+    #[test]
+    fn corrupt_entry_returns_error() {
+        let db: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let chain = BlockChain::with_storage(db.clone(), ChainConfig::default()).unwrap();
+        let genesis = chain.genesis_hash;
+        drop(chain);
+
+        let k = DbKey::new(Column::Block, &genesis);
+        db.put(k.as_bytes(), &[1, 2, 3]).unwrap();
+        assert!(matches!(
+            BlockChain::with_storage(db, ChainConfig::default()),
+            Err(Error::Codec(_))
+        ));
     }
 }
@@ -0,0 +1,309 @@
+/// 链的参数
+///
+/// 可以从 TOML 文件读取，命令行参数覆盖文件中的值，例如同时运行多个测试网络：
+///
+/// ```toml
+/// data_dir = "testnet_db"
+/// network_id = 2
+/// genesis_message = "This is testnet"
+/// initial_bits = 0x2100ffff
+/// block_reward = 50
+/// halving_interval = 210000
+/// coinbase_maturity = 100
+/// retarget_interval = 20
+/// max_block_size = 1048576
+/// hasher = "double_sha256"
+/// listen_addr = "127.0.0.1:7879"
+/// peers = ["127.0.0.1:7878"]
+/// max_peers = 8
+/// rpc_addr = "127.0.0.1:7881"
+/// ```
+///
+/// ```text
+/// main --config testnet.toml --data-dir testnet2_db --peers 127.0.0.1:7878,127.0.0.1:7879
+/// ```
+///
+/// 没有出现的项使用默认值，默认值与之前写死的常量一致，已有的数据库可以继续使用。
+///
+use crate::core::block::MAX_BLOCK_SIZE;
+use crate::core::pow::{self, ProofOfWork};
+use crate::error::{Error, Result};
+use crate::utils::coder::Hasher;
+use crate::utils::hex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChainConfig {
+    /// 数据库目录，相对路径相对于当前目录
+    pub data_dir: PathBuf,
+    /// 网络 id ，写在 genesis 的 nonce 中，不同网络的 genesis 不同
+    pub network_id: u32,
+    /// genesis coinbase 中的数据
+    pub genesis_message: String,
+    /// genesis 的时间戳
+    pub genesis_time: i64,
+    /// genesis 的 bits ，也是最低难度
+    pub initial_bits: u32,
+    /// 最初的出块奖励
+    pub block_reward: u64,
+    /// 每 halving_interval 个区块出块奖励减半
+    pub halving_interval: u64,
+    /// coinbase 要经过多少个区块才能花费
+    pub coinbase_maturity: u64,
+    /// 每 retarget_interval 个区块调整一次难度
+    pub retarget_interval: u64,
+    /// wire 编码后的区块最大字节数
+    pub max_block_size: usize,
+    /// pow 尝试的最大 nonce
+    pub max_nonce: u32,
+    /// pow 的线程数，0 表示 CPU 核数
+    pub miner_threads: usize,
+    /// 区块头、交易、merkle 树和状态树的 hash 函数，不同的 hash 函数 genesis 不同
+    pub hasher: Hasher,
+    /// 出块奖励的接收地址
+    #[serde(serialize_with = "ser_address", deserialize_with = "de_address")]
+    pub miner_address: [u8; 32],
+    /// 提交区块时等待数据落盘
+    pub sync: bool,
+    /// p2p 监听的地址，端口为 0 时由系统分配
+    pub listen_addr: String,
+    /// 启动时连接的节点，命令行中用逗号分隔
+    pub peers: Vec<String>,
+    /// 最多连接的节点数，包括主动连接和被连接
+    pub max_peers: usize,
+    /// JSON-RPC 监听的地址，默认只接受本机的连接
+    pub rpc_addr: String,
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        ChainConfig {
+            data_dir: PathBuf::from("blockchain_db"),
+            network_id: 0,
+            genesis_message: "This is genesis".to_string(),
+            // 2021-01-01 00:00:00 UTC
+            genesis_time: 1609459200,
+            // bitcoin 是 0x1d00ffff ，为了 pow 快速计算，暂时用这个数值
+            initial_bits: 0x2100FFFF,
+            block_reward: 50,
+            halving_interval: 210_000,
+            coinbase_maturity: 100,
+            retarget_interval: pow::RETARGET_INTERVAL,
+            max_block_size: MAX_BLOCK_SIZE,
+            max_nonce: pow::MAX_NONCE,
+            miner_threads: 0,
+            hasher: Hasher::Sha3_256,
+            miner_address: [8; 32],
+            sync: false,
+            listen_addr: "127.0.0.1:7878".to_string(),
+            peers: Vec::new(),
+            max_peers: 8,
+            rpc_addr: "127.0.0.1:7880".to_string(),
+        }
+    }
+}
+
+fn ser_address<S: Serializer>(address: &[u8; 32], s: S) -> std::result::Result<S::Ok, S::Error> {
+    s.serialize_str(&hex::encode(address))
+}
+
+fn de_address<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<[u8; 32], D::Error> {
+    let s = String::deserialize(d)?;
+    hex::decode_32(&s).ok_or_else(|| serde::de::Error::custom("expected 32 bytes hex"))
+}
+
+fn parse<T: FromStr>(name: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| Error::Config(format!("invalid value for {}: {}", name, value)))
+}
+
+/// 十进制或 0x 开头的十六进制
+fn parse_bits(value: &str) -> Result<u32> {
+    let bits = match value.strip_prefix("0x") {
+        Some(h) => u32::from_str_radix(h, 16).ok(),
+        None => value.parse().ok(),
+    };
+    bits.ok_or_else(|| Error::Config(format!("invalid value for initial_bits: {}", value)))
+}
+
+impl ChainConfig {
+    /// 读取 TOML 文件
+    pub fn load(path: &Path) -> Result<ChainConfig> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("failed to read {}: {}", path.display(), e)))?;
+        let config: ChainConfig = toml::from_str(&text)
+            .map_err(|e| Error::Config(format!("failed to parse {}: {}", path.display(), e)))?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// 命令行参数：--config <file> 先读取配置文件，其余的 --<name> <value> 覆盖其中的值
+    pub fn from_args<I>(args: I) -> Result<ChainConfig>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut pairs = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let name = match arg.strip_prefix("--") {
+                Some(name) => name.to_string(),
+                None => return Err(Error::Config(format!("unexpected argument: {}", arg))),
+            };
+            let value = args
+                .next()
+                .ok_or_else(|| Error::Config(format!("missing value for --{}", name)))?;
+            pairs.push((name, value));
+        }
+
+        let mut config = match pairs.iter().find(|(name, _)| name == "config") {
+            Some((_, path)) => Self::load(Path::new(path))?,
+            None => ChainConfig::default(),
+        };
+        for (name, value) in pairs.iter().filter(|(name, _)| name != "config") {
+            config.set(name, value)?;
+        }
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// 用字符串设置一项，name 与 TOML 中的 key 相同，也可以用 - 代替 _
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        match name.replace('-', "_").as_str() {
+            "data_dir" => self.data_dir = PathBuf::from(value),
+            "network_id" => self.network_id = parse(name, value)?,
+            "genesis_message" => self.genesis_message = value.to_string(),
+            "genesis_time" => self.genesis_time = parse(name, value)?,
+            "initial_bits" => self.initial_bits = parse_bits(value)?,
+            "block_reward" => self.block_reward = parse(name, value)?,
+            "halving_interval" => self.halving_interval = parse(name, value)?,
+            "coinbase_maturity" => self.coinbase_maturity = parse(name, value)?,
+            "retarget_interval" => self.retarget_interval = parse(name, value)?,
+            "max_block_size" => self.max_block_size = parse(name, value)?,
+            "max_nonce" => self.max_nonce = parse(name, value)?,
+            "miner_threads" => self.miner_threads = parse(name, value)?,
+            "hasher" => self.hasher = value.parse()?,
+            "miner_address" => {
+                self.miner_address = hex::decode_32(value).ok_or_else(|| {
+                    Error::Config(format!("invalid value for miner_address: {}", value))
+                })?
+            }
+            "sync" => self.sync = parse(name, value)?,
+            "listen_addr" => self.listen_addr = value.to_string(),
+            "peers" => {
+                self.peers = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            }
+            "max_peers" => self.max_peers = parse(name, value)?,
+            "rpc_addr" => self.rpc_addr = value.to_string(),
+            _ => return Err(Error::Config(format!("unknown option: {}", name))),
+        }
+        Ok(())
+    }
+
+    /// 参数是否可用
+    pub fn validate(&self) -> Result<()> {
+        if ProofOfWork::bits_to_target(self.initial_bits).is_zero() {
+            return Err(Error::Config(format!(
+                "invalid initial_bits: {:#x}",
+                self.initial_bits
+            )));
+        }
+        // 难度调整至少需要两个区块的时间间隔
+        if self.retarget_interval < 2 {
+            return Err(Error::Config(
+                "retarget_interval must be at least 2".to_string(),
+            ));
+        }
+        if self.halving_interval == 0 {
+            return Err(Error::Config("halving_interval must not be 0".to_string()));
+        }
+        if self.max_block_size == 0 {
+            return Err(Error::Config("max_block_size must not be 0".to_string()));
+        }
+        Ok(())
+    }
+
+    /// height 高度的出块奖励，每 halving_interval 个区块减半，最终为 0
+    pub fn subsidy(&self, height: u64) -> u64 {
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 {
+            return 0;
+        }
+        self.block_reward >> halvings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChainConfig;
+    use crate::utils::coder::Hasher;
+    use std::path::PathBuf;
+
+    #[test]
+    fn config_works() {
+        let config: ChainConfig = toml::from_str(
+            r#"
+            data_dir = "testnet_db"
+            network_id = 2
+            initial_bits = 0x2000ffff
+            hasher = "blake2b"
+            miner_address = "0101010101010101010101010101010101010101010101010101010101010101"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.data_dir, PathBuf::from("testnet_db"));
+        assert_eq!(config.network_id, 2);
+        assert_eq!(config.initial_bits, 0x2000ffff);
+        assert_eq!(config.miner_address, [1; 32]);
+        assert_eq!(config.hasher, Hasher::Blake2b);
+        assert_eq!(config.block_reward, ChainConfig::default().block_reward);
+
+        // 写出再读回
+        let text = toml::to_string(&config).unwrap();
+        assert_eq!(toml::from_str::<ChainConfig>(&text).unwrap(), config);
+
+        assert!(toml::from_str::<ChainConfig>("unknown = 1").is_err());
+
+        let args = ["--data-dir", "a_db", "--initial_bits", "0x2000ffff"];
+        let config = ChainConfig::from_args(args.iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(config.data_dir, PathBuf::from("a_db"));
+        assert_eq!(config.initial_bits, 0x2000ffff);
+
+        let args = ["--peers", "127.0.0.1:1, 127.0.0.1:2"];
+        let config = ChainConfig::from_args(args.iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(config.peers, vec!["127.0.0.1:1", "127.0.0.1:2"]);
+
+        let args = ["--retarget-interval", "1"];
+        assert!(ChainConfig::from_args(args.iter().map(|s| s.to_string())).is_err());
+        // 超过 256 位的 target
+        let args = ["--initial-bits", "0x2101ffff"];
+        assert!(ChainConfig::from_args(args.iter().map(|s| s.to_string())).is_err());
+        let args = ["--block-reward"];
+        assert!(ChainConfig::from_args(args.iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn subsidy_works() {
+        let config = ChainConfig {
+            halving_interval: 10,
+            ..ChainConfig::default()
+        };
+        assert_eq!(config.subsidy(0), 50);
+        assert_eq!(config.subsidy(9), 50);
+        assert_eq!(config.subsidy(10), 25);
+        assert_eq!(config.subsidy(25), 12);
+        assert_eq!(config.subsidy(10 * 64), 0);
+        assert_eq!(config.subsidy(u64::MAX), 0);
+    }
+}
@@ -0,0 +1,483 @@
+/// 交易池：等待打包的交易
+///
+/// - 加入时验证签名、nonce 和余额，按 hash 去重
+/// - 同一个 (from, nonce) 只保留一笔，手续费和手续费率都更高的可以替换（replace-by-fee）
+/// - 按手续费率 fee / size 排序，超过大小或存放太久的交易被淘汰
+/// - 出块时按手续费率选出不超过区块大小的交易，同一个账户的交易按 nonce 顺序
+///
+use crate::core::account::Account;
+use crate::core::transaction::Transaction;
+use crate::core::wire;
+use crate::error;
+use crate::utils::coder::Hasher;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::error::Error;
+use std::fmt;
+
+/// 交易池最大字节数
+pub const MAX_MEMPOOL_SIZE: usize = 16 * 1024 * 1024;
+/// 交易最多在池中保留一天，秒
+pub const MAX_MEMPOOL_AGE: i64 = 24 * 60 * 60;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MempoolError {
+    /// hash 与交易内容不一致
+    BadHash,
+    AlreadyKnown,
+    Coinbase,
+    BadSignature,
+    /// nonce 已经被链上使用
+    NonceTooLow,
+    /// 余额不足以支付该账户池中所有交易
+    InsufficientBalance,
+    /// 替换同一个 (from, nonce) 的交易需要更高的 fee 和手续费率
+    FeeTooLow,
+    /// 交易池已满，手续费率太低
+    PoolFull,
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MempoolError::BadHash => write!(f, "hash does not match transaction"),
+            MempoolError::AlreadyKnown => write!(f, "transaction already in mempool"),
+            MempoolError::Coinbase => write!(f, "coinbase is not accepted"),
+            MempoolError::BadSignature => write!(f, "bad signature"),
+            MempoolError::NonceTooLow => write!(f, "nonce too low"),
+            MempoolError::InsufficientBalance => write!(f, "insufficient balance"),
+            MempoolError::FeeTooLow => write!(f, "fee too low to replace transaction"),
+            MempoolError::PoolFull => write!(f, "mempool is full"),
+        }
+    }
+}
+
+impl Error for MempoolError {}
+
+struct Entry {
+    tx: Transaction,
+    /// wire 编码的字节数，与区块大小（ChainConfig.max_block_size）的计算方式相同
+    size: usize,
+    /// 加入时间
+    time: i64,
+}
+
+impl Entry {
+    /// 比较手续费率 fee / size ，相同时按 hash 保证顺序确定
+    fn cmp_fee_rate(&self, other: &Entry) -> Ordering {
+        let a = self.tx.fee as u128 * other.size as u128;
+        let b = other.tx.fee as u128 * self.size as u128;
+        a.cmp(&b).then_with(|| other.tx.hash.cmp(&self.tx.hash))
+    }
+
+    /// 替换同一个 (from, nonce) 的 old ：fee 和手续费率都必须更高
+    fn replaces(&self, old: &Entry) -> bool {
+        self.tx.fee > old.tx.fee
+            && self.tx.fee as u128 * old.size as u128 > old.tx.fee as u128 * self.size as u128
+    }
+}
+
+/// select 时每个账户下一笔可以打包的交易
+struct Candidate<'a> {
+    entry: &'a Entry,
+    /// 在该账户按 nonce 排序的交易中的位置
+    index: usize,
+}
+
+impl PartialEq for Candidate<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate<'_> {}
+
+impl PartialOrd for Candidate<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.entry.cmp_fee_rate(other.entry)
+    }
+}
+
+pub struct Mempool {
+    entries: HashMap<[u8; 32], Entry>,
+    /// (from, nonce) -> tx hash ，按 from 范围查找一个账户的所有交易
+    senders: BTreeMap<([u8; 32], u64), [u8; 32]>,
+    total_size: usize,
+    max_size: usize,
+    max_age: i64,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(MAX_MEMPOOL_SIZE, MAX_MEMPOOL_AGE)
+    }
+}
+
+impl Mempool {
+    pub fn new(max_size: usize, max_age: i64) -> Mempool {
+        Mempool {
+            entries: HashMap::new(),
+            senders: BTreeMap::new(),
+            total_size: 0,
+            max_size,
+            max_age,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 所有交易占用的字节数
+    pub fn size(&self) -> usize {
+        self.total_size
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &[u8; 32]) -> Option<&Transaction> {
+        self.entries.get(hash).map(|e| &e.tx)
+    }
+
+    pub fn transactions(&self) -> Vec<&Transaction> {
+        self.entries.values().map(|e| &e.tx).collect()
+    }
+
+    /// # Arguments
+    /// * sender - tx.from 在链上的账户状态
+    /// * now - 当前时间，用于淘汰过期交易
+    /// * hasher - 链的 hash 函数，ChainConfig.hasher
+    pub fn add(
+        &mut self,
+        tx: Transaction,
+        sender: &Account,
+        now: i64,
+        hasher: Hasher,
+    ) -> Result<(), MempoolError> {
+        if !tx.verify_hash(hasher) {
+            return Err(MempoolError::BadHash);
+        }
+        if self.entries.contains_key(&tx.hash) {
+            return Err(MempoolError::AlreadyKnown);
+        }
+        if tx.is_coinbase() {
+            return Err(MempoolError::Coinbase);
+        }
+        if !tx.verify() {
+            return Err(MempoolError::BadSignature);
+        }
+        if tx.nonce <= sender.nonce {
+            return Err(MempoolError::NonceTooLow);
+        }
+
+        let size = wire::encode_tx(&tx).len();
+        let entry = Entry {
+            tx,
+            size,
+            time: now,
+        };
+        let tx = &entry.tx;
+        let replaced = self.senders.get(&(tx.from, tx.nonce)).cloned();
+        if let Some(old) = replaced.and_then(|hash| self.entries.get(&hash)) {
+            if !entry.replaces(old) {
+                return Err(MempoolError::FeeTooLow);
+            }
+        }
+
+        // 该账户池中其他交易加上这一笔的花费
+        let pending: u128 = self
+            .senders
+            .range((tx.from, 0)..=(tx.from, u64::MAX))
+            .filter(|(_, hash)| Some(**hash) != replaced)
+            .map(|(_, hash)| {
+                let e = &self.entries[hash].tx;
+                e.amount as u128 + e.fee as u128
+            })
+            .sum();
+        if pending + tx.amount as u128 + tx.fee as u128 > sender.balance as u128 {
+            return Err(MempoolError::InsufficientBalance);
+        }
+
+        if let Some(hash) = replaced {
+            self.remove(&hash);
+        }
+        let hash = tx.hash;
+        self.senders.insert((tx.from, tx.nonce), hash);
+        self.entries.insert(hash, entry);
+        self.total_size += size;
+
+        self.evict(now);
+        if !self.entries.contains_key(&hash) {
+            return Err(MempoolError::PoolFull);
+        }
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, hash: &[u8; 32]) -> Option<Transaction> {
+        let entry = self.entries.remove(hash)?;
+        self.senders.remove(&(entry.tx.from, entry.tx.nonce));
+        self.total_size -= entry.size;
+
+        Some(entry.tx)
+    }
+
+    /// 淘汰过期的交易，超过 max_size 时淘汰手续费率最低的交易
+    pub fn evict(&mut self, now: i64) {
+        let expired: Vec<[u8; 32]> = self
+            .entries
+            .values()
+            .filter(|e| now - e.time > self.max_age)
+            .map(|e| e.tx.hash)
+            .collect();
+        for hash in expired {
+            self.remove(&hash);
+        }
+
+        while self.total_size > self.max_size {
+            let lowest = self
+                .entries
+                .values()
+                .min_by(|a, b| a.cmp_fee_rate(b))
+                .map(|e| e.tx.hash);
+            match lowest {
+                Some(hash) => self.remove(&hash),
+                None => break,
+            };
+        }
+    }
+
+    /// 删除 nonce 已经在链上使用的交易（已打包或冲突）
+    ///
+    /// # Arguments
+    /// * nonce_of - 账户在链上的 nonce
+    pub fn prune<F>(&mut self, nonce_of: F) -> error::Result<()>
+    where
+        F: Fn(&[u8; 32]) -> error::Result<u64>,
+    {
+        let mut stale = Vec::new();
+        for e in self.entries.values() {
+            if e.tx.nonce <= nonce_of(&e.tx.from)? {
+                stale.push(e.tx.hash);
+            }
+        }
+        for hash in stale {
+            self.remove(&hash);
+        }
+        Ok(())
+    }
+
+    /// 按手续费率选出总大小不超过 max_size 的交易
+    /// 同一个账户的交易必须从链上 nonce + 1 开始连续
+    pub fn select<F>(&self, nonce_of: F, max_size: usize) -> error::Result<Vec<Transaction>>
+    where
+        F: Fn(&[u8; 32]) -> error::Result<u64>,
+    {
+        let mut by_sender: HashMap<[u8; 32], Vec<&Entry>> = HashMap::new();
+        for e in self.entries.values() {
+            by_sender.entry(e.tx.from).or_default().push(e);
+        }
+
+        let mut heap = BinaryHeap::new();
+        for entries in by_sender.values_mut() {
+            entries.sort_by_key(|e| e.tx.nonce);
+            if entries[0].tx.nonce == nonce_of(&entries[0].tx.from)? + 1 {
+                heap.push(Candidate {
+                    entry: entries[0],
+                    index: 0,
+                });
+            }
+        }
+
+        let mut selected = Vec::new();
+        let mut size = 0usize;
+        while let Some(Candidate { entry, index }) = heap.pop() {
+            // 放不下，该账户之后的交易也不能打包
+            if size + entry.size > max_size {
+                continue;
+            }
+            size += entry.size;
+            selected.push(entry.tx.clone());
+
+            let entries = &by_sender[&entry.tx.from];
+            if let Some(next) = entries.get(index + 1) {
+                if next.tx.nonce == entry.tx.nonce + 1 {
+                    heap.push(Candidate {
+                        entry: next,
+                        index: index + 1,
+                    });
+                }
+            }
+        }
+
+        Ok(selected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Entry, Mempool, MempoolError};
+    use crate::core::account::Account;
+    use crate::core::transaction::Transaction;
+    use crate::core::wire;
+    use crate::utils::coder::Hasher;
+    use crate::utils::keypair::KeyPair;
+
+    const H: Hasher = Hasher::Sha3_256;
+
+    fn signed_tx(keypair: &KeyPair, nonce: u64, fee: u64) -> Transaction {
+        let mut tx = Transaction::new(keypair.address(), [3; 32], 1, fee, nonce, H);
+        tx.sign(keypair).unwrap();
+        tx
+    }
+
+    fn rich(keypair: &KeyPair) -> Account {
+        let mut account = Account::with_address(keypair.address());
+        account.balance = 1000;
+        account
+    }
+
+    #[test]
+    fn add_works() {
+        let mut pool = Mempool::default();
+        let alice = KeyPair::generate();
+
+        let tx = signed_tx(&alice, 1, 1);
+        assert_eq!(pool.add(tx.clone(), &rich(&alice), 0, H), Ok(()));
+        assert_eq!(
+            pool.add(tx.clone(), &rich(&alice), 0, H),
+            Err(MempoolError::AlreadyKnown)
+        );
+
+        let poor = Account::with_address(alice.address());
+        let tx2 = signed_tx(&alice, 2, 1);
+        assert_eq!(
+            pool.add(tx2, &poor, 0, H),
+            Err(MempoolError::InsufficientBalance)
+        );
+        let tx0 = signed_tx(&alice, 0, 1);
+        assert_eq!(
+            pool.add(tx0, &rich(&alice), 0, H),
+            Err(MempoolError::NonceTooLow)
+        );
+
+        let mut bad = signed_tx(&alice, 2, 1);
+        bad.amount = 2;
+        assert_eq!(
+            pool.add(bad.clone(), &rich(&alice), 0, H),
+            Err(MempoolError::BadHash)
+        );
+        bad.set_hash(H);
+        assert_eq!(
+            pool.add(bad, &rich(&alice), 0, H),
+            Err(MempoolError::BadSignature)
+        );
+
+        // replace-by-fee
+        let mut same_fee = Transaction::new(alice.address(), [4; 32], 1, 1, 1, H);
+        same_fee.sign(&alice).unwrap();
+        assert_eq!(
+            pool.add(same_fee, &rich(&alice), 0, H),
+            Err(MempoolError::FeeTooLow)
+        );
+        let higher = signed_tx(&alice, 1, 5);
+        assert_eq!(pool.add(higher.clone(), &rich(&alice), 0, H), Ok(()));
+        assert_eq!(pool.len(), 1);
+        assert!(!pool.contains(&tx.hash));
+        assert!(pool.contains(&higher.hash));
+
+        // fee 更高但手续费率更低
+        let old = Entry {
+            tx: signed_tx(&alice, 1, 10),
+            size: 100,
+            time: 0,
+        };
+        let bigger = Entry {
+            tx: signed_tx(&alice, 1, 15),
+            size: 200,
+            time: 0,
+        };
+        assert!(!bigger.replaces(&old));
+        let smaller = Entry {
+            size: 140,
+            ..bigger
+        };
+        assert!(smaller.replaces(&old));
+    }
+
+    #[test]
+    fn select_works() {
+        let mut pool = Mempool::default();
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+
+        // alice 的高手续费交易在低手续费交易之后，必须按 nonce 顺序
+        let a1 = signed_tx(&alice, 1, 1);
+        let a2 = signed_tx(&alice, 2, 9);
+        let b1 = signed_tx(&bob, 1, 5);
+        // nonce 不连续，不能打包
+        let b3 = signed_tx(&bob, 3, 100);
+        for tx in [a1.clone(), a2.clone(), b1.clone(), b3] {
+            let sender = if tx.from == alice.address() {
+                rich(&alice)
+            } else {
+                rich(&bob)
+            };
+            pool.add(tx, &sender, 0, H).unwrap();
+        }
+
+        let hashes = |txs: Vec<Transaction>| txs.iter().map(|tx| tx.hash).collect::<Vec<_>>();
+        let selected = pool.select(|_| Ok(0), usize::MAX).unwrap();
+        assert_eq!(hashes(selected), vec![b1.hash, a1.hash, a2.hash]);
+
+        // 只放得下一笔
+        let size = wire::encode_tx(&b1).len();
+        let selected = pool.select(|_| Ok(0), size).unwrap();
+        assert_eq!(hashes(selected), vec![b1.hash]);
+
+        // 链上 alice 的 nonce 已经是 1
+        let nonce_of = |address: &[u8; 32]| Ok(if *address == alice.address() { 1 } else { 0 });
+        pool.prune(nonce_of).unwrap();
+        assert!(!pool.contains(&a1.hash));
+        let selected = pool.select(nonce_of, usize::MAX).unwrap();
+        assert_eq!(hashes(selected), vec![a2.hash, b1.hash]);
+    }
+
+    #[test]
+    fn evict_works() {
+        let alice = KeyPair::generate();
+        let tx1 = signed_tx(&alice, 1, 1);
+        let size = wire::encode_tx(&tx1).len();
+        let mut pool = Mempool::new(size * 2, 100);
+
+        pool.add(tx1.clone(), &rich(&alice), 0, H).unwrap();
+        pool.add(signed_tx(&alice, 2, 5), &rich(&alice), 0, H)
+            .unwrap();
+        // 手续费率最低的被淘汰
+        let tx3 = signed_tx(&alice, 3, 3);
+        pool.add(tx3.clone(), &rich(&alice), 10, H).unwrap();
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.contains(&tx1.hash));
+        assert_eq!(
+            pool.add(signed_tx(&alice, 4, 0), &rich(&alice), 10, H),
+            Err(MempoolError::PoolFull)
+        );
+
+        // 过期
+        pool.evict(105);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.contains(&tx3.hash));
+    }
+}
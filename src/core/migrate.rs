@@ -0,0 +1,336 @@
+/// 旧版本数据库的转换
+///
+/// 旧版本的 key 是 32 字节的 MyKey ：U256 的 4 个 u64（低位在前）按小端排列，
+/// 区块和 trie 节点直接用 hash 作为 key ，tail 用 U256::from("tail") ，
+/// 累计工作量和索引用 hash(tag + id) ，所有数据在同一个 key 空间中。
+///
+/// 只支持有累计工作量和索引、还没有按列加前缀的版本。最初版本的数据库只有区块和 tail ，
+/// 交易没有签名，不能转换；区块 hash 的计算方式与当前不同的数据库转换后也不能打开。
+/// 这两种数据库 migrate 都在写入之前返回错误，数据库不变。
+///
+/// 转换后的布局见 bcdb.rs 。
+///
+use crate::core::bcdb::BlockChainDb;
+use crate::core::block::Block;
+use crate::core::blockchain::BlockChain;
+use crate::core::config::ChainConfig;
+use crate::core::storage::{Storage, WriteBatch};
+use crate::core::trie::{self, EMPTY_ROOT};
+use crate::core::validation;
+use crate::core::wire;
+use crate::error::{Error, Result};
+use crate::utils::coder::{self, Hasher};
+use crate::utils::key::{Column, DbKey, U256};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MigrateStats {
+    pub blocks: u64,
+    pub nodes: u64,
+}
+
+/// 旧版本 MyKey 的字节，与小端机器上 transmute 的结果一致
+fn legacy_key(v: U256) -> [u8; 32] {
+    let mut k = [0u8; 32];
+    for (i, limb) in v.0.iter().enumerate() {
+        k[8 * i..8 * i + 8].copy_from_slice(&limb.to_le_bytes());
+    }
+    k
+}
+
+fn legacy_hash_key(hash: &[u8; 32]) -> [u8; 32] {
+    legacy_key(U256::from(hash))
+}
+
+fn legacy_index_key(tag: &[u8], id: &[u8], hasher: Hasher) -> [u8; 32] {
+    legacy_hash_key(&hasher.hash(&[tag, id].concat()))
+}
+
+fn legacy_tail_key() -> [u8; 32] {
+    legacy_key(U256::from("tail".as_bytes()))
+}
+
+/// 数据库中有旧版本的 tail
+pub fn is_legacy(db: &dyn Storage) -> Result<bool> {
+    Ok(db.get(&legacy_tail_key())?.is_some())
+}
+
+/// 转换旧布局的数据库，旧的 key 在链可以打开之后才删除
+///
+/// 1. 检查主链：区块必须按当前的编码和 hasher 计算，genesis 与 config 一致，否则不写入任何数据
+/// 2. 写入新布局的 key 和 recover 标记，旧的 key 保留
+/// 3. 打开链（recover 补上缺少的累计工作量和 trie 节点），失败时删除新写入的数据，数据库回到转换之前
+/// 4. 删除旧的 key
+///
+/// 只转换从 tail 可以到达的主链区块和它们的状态，分叉上的区块留在原来的 key 下。
+/// 累计工作量和索引的旧 key 由 hash 计算，hasher 是链的 hash 函数，ChainConfig.hasher 。
+///
+/// 最初版本（没有累计工作量和索引，交易没有签名）写入的数据库不能转换，返回错误。
+pub fn migrate(db: Arc<dyn Storage>, config: &ChainConfig) -> Result<MigrateStats> {
+    let plan = plan(db.as_ref(), config.hasher)?;
+    check_chain(&plan.blocks, config)?;
+
+    db.write(plan.batch)?;
+    if let Err(e) = BlockChain::with_storage(db.clone(), config.clone()) {
+        rollback(db.as_ref())?;
+        return Err(e);
+    }
+
+    let mut batch = WriteBatch::new();
+    for k in &plan.old_keys {
+        batch.delete(k);
+    }
+    db.write(batch)?;
+
+    Ok(plan.stats)
+}
+
+/// 转换需要的写入，还没有写入数据库
+struct Plan {
+    /// 新布局的 key 、tail 、版本和 recover 标记
+    batch: WriteBatch,
+    /// 转换完成后删除的旧 key
+    old_keys: Vec<Vec<u8>>,
+    /// 主链区块，从 tail 到 genesis
+    blocks: Vec<Block>,
+    stats: MigrateStats,
+}
+
+fn plan(db: &dyn Storage, hasher: Hasher) -> Result<Plan> {
+    let tail_v = db
+        .get(&legacy_tail_key())?
+        .ok_or_else(|| Error::Storage("legacy tail not found".to_string()))?;
+    let tail: [u8; 32] = coder::deserialize(&tail_v)?;
+
+    let mut plan = Plan {
+        batch: WriteBatch::new(),
+        old_keys: Vec::new(),
+        blocks: Vec::new(),
+        stats: MigrateStats::default(),
+    };
+    let batch = &mut plan.batch;
+    let mut indexed = false;
+    let mut roots = Vec::new();
+    let mut hash = tail;
+    loop {
+        let old = legacy_hash_key(&hash);
+        let v = db
+            .get(&old)?
+            .ok_or_else(|| Error::Storage(format!("block missing: {:?}", hash)))?;
+        let b: Block = coder::deserialize(&v)
+            .map_err(|e| Error::Storage(format!("cannot decode block {:?}: {}", hash, e)))?;
+        batch.put(DbKey::new(Column::Block, &hash).as_bytes(), &v);
+        plan.old_keys.push(old.to_vec());
+
+        let work_key = legacy_index_key(b"work", &hash, hasher);
+        if let Some(work) = db.get(&work_key)?.filter(|w| w.len() == 32) {
+            let mut v = work;
+            v.extend_from_slice(&wire::encode_header(&b.header));
+            batch.put(DbKey::new(Column::Header, &hash).as_bytes(), &v);
+            indexed = true;
+        }
+        plan.old_keys.push(work_key.to_vec());
+
+        let height_key = legacy_index_key(b"height", &b.header.height.to_be_bytes(), hasher);
+        indexed |= db.get(&height_key)?.is_some();
+        plan.old_keys.push(height_key.to_vec());
+        BlockChainDb::write_height(batch, b.header.height, &hash);
+        for (i, tx) in b.transactions.iter().enumerate() {
+            plan.old_keys
+                .push(legacy_index_key(b"tx", &tx.hash, hasher).to_vec());
+            BlockChainDb::write_tx_index(batch, &tx.hash, &hash, i as u64);
+        }
+
+        roots.push(b.header.state_root);
+        plan.stats.blocks += 1;
+        let height = b.header.height;
+        hash = b.header.pre_hash;
+        plan.blocks.push(b);
+        if height == 0 {
+            break;
+        }
+    }
+    // 最初的版本只写入区块和 tail
+    if !indexed {
+        return Err(Error::Storage(
+            "database was written by the original version (no work or height index, unsigned \
+             transactions) and cannot be migrated, remove it and sync from peers"
+                .to_string(),
+        ));
+    }
+
+    // 所有主链区块的状态树，共享的节点只转换一次
+    let mut seen = HashSet::new();
+    let mut stack: Vec<[u8; 32]> = roots.into_iter().filter(|r| *r != EMPTY_ROOT).collect();
+    while let Some(hash) = stack.pop() {
+        if !seen.insert(hash) {
+            continue;
+        }
+        let old = legacy_hash_key(&hash);
+        let data = match db.get(&old)? {
+            Some(data) => data,
+            None => continue,
+        };
+        stack.extend(trie::node_children(&data)?);
+        batch.put(DbKey::new(Column::State, &hash).as_bytes(), &data);
+        plan.old_keys.push(old.to_vec());
+        plan.stats.nodes += 1;
+    }
+
+    batch.put(DbKey::meta("tail").as_bytes(), &tail_v);
+    plan.old_keys.push(legacy_tail_key().to_vec());
+    BlockChainDb::write_version(batch);
+    BlockChainDb::write_recover(batch);
+
+    Ok(plan)
+}
+
+/// 区块必须能用当前的编码和 hasher 验证，genesis 与 config 一致，否则转换后也不能打开
+fn check_chain(blocks: &[Block], config: &ChainConfig) -> Result<()> {
+    // 从 genesis 开始检查
+    for b in blocks.iter().rev() {
+        if b.header.height == 0 {
+            let genesis = BlockChain::get_genesis_block(config).hash;
+            if b.hash != genesis {
+                return Err(Error::Storage(format!(
+                    "genesis mismatch, expected {:?} found {:?}: the database was written by a \
+                     version with different block hashing, or with another config",
+                    genesis, b.hash
+                )));
+            }
+        } else if let Err(e) = validation::check_block(b, config.max_block_size, config.hasher) {
+            return Err(Error::Storage(format!(
+                "block {:?} does not match the current encoding: {}",
+                b.hash, e
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// 删除新布局的 key ，旧布局的 key 都是 32 字节
+fn rollback(db: &dyn Storage) -> Result<()> {
+    let mut batch = WriteBatch::new();
+    for (k, _) in db.iter_prefix(&[])? {
+        if k.len() != 32 {
+            batch.delete(&k);
+        }
+    }
+    db.write(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_legacy, migrate, plan};
+    use crate::core::bcdb::BlockChainDb;
+    use crate::core::block::Block;
+    use crate::core::blockchain::BlockChain;
+    use crate::core::config::ChainConfig;
+    use crate::core::state::StateDb;
+    use crate::core::storage::{MemoryStorage, Storage, WriteBatch};
+    use crate::core::trie;
+    use crate::core::wire;
+    use crate::error::Error;
+    use crate::utils::coder::{self, Hasher};
+    use crate::utils::hex;
+    use crate::utils::key::{Column, DbKey};
+    use std::sync::Arc;
+
+    /// 文件是 bincode 编码的所有 (key, value)
+    fn load(fixture: &[u8]) -> Arc<dyn Storage> {
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = coder::deserialize(fixture).unwrap();
+        let mut batch = WriteBatch::new();
+        for (k, v) in &entries {
+            batch.put(k, v);
+        }
+        let db = MemoryStorage::new();
+        db.write(batch).unwrap();
+        Arc::new(db)
+    }
+
+    fn dump(db: &dyn Storage) -> Vec<(Vec<u8>, Vec<u8>)> {
+        db.iter_prefix(&[]).unwrap()
+    }
+
+    /// 最初版本的 main 写入的数据库：genesis 之后出了两个块，每个块有 coinbase 和一笔没有签名的交易
+    #[test]
+    fn baseline_fixture_works() {
+        let db = load(include_bytes!("testdata/baseline_db.bin"));
+        assert!(is_legacy(db.as_ref()).unwrap());
+        let before = dump(db.as_ref());
+
+        match migrate(db.clone(), &ChainConfig::default()) {
+            Err(Error::Storage(e)) => assert!(e.contains("original version"), "{}", e),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(dump(db.as_ref()), before);
+        assert!(BlockChain::with_storage(db, ChainConfig::default()).is_err());
+    }
+
+    /// 改为按列加前缀的 key 之前的版本写入的数据库：genesis 之后出了两个块，第一个块中有
+    /// Account::new([7; 32]) 发出的一笔交易，矿工是 ChainConfig::default().miner_address 。
+    #[test]
+    fn legacy_fixture_works() {
+        let db = load(include_bytes!("testdata/legacy_db.bin"));
+        assert!(is_legacy(db.as_ref()).unwrap());
+        let before = dump(db.as_ref());
+
+        // 区块按旧版本的 hash 计算，转换后也不能打开，不写入任何数据
+        match migrate(db.clone(), &ChainConfig::default()) {
+            Err(Error::Storage(e)) => assert!(e.contains("genesis mismatch"), "{}", e),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(dump(db.as_ref()), before);
+
+        // 只检查转换后的布局
+        let h = Hasher::Sha3_256;
+        let hash = |s: &str| hex::decode_32(s).unwrap();
+        let tail = hash("93a701ee32fc244a23904f2286d85a845f6c4a07d3b1254681ce5ed2b23aa4fb");
+        let b1 = hash("6adaff724b3ae6478d9ee3548782fe34b1003bbb05b101d630ebcc0632e23796");
+        let tx = hash("616b4456585ac7c01849f81568656b90c33eb7a764baae9bf5fe14b2675f2c81");
+        let alice = hash("ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c");
+
+        let plan = plan(db.as_ref(), h).unwrap();
+        assert_eq!(plan.stats.blocks, 3);
+        db.write(plan.batch).unwrap();
+        let mut batch = WriteBatch::new();
+        for k in &plan.old_keys {
+            batch.delete(k);
+        }
+        db.write(batch).unwrap();
+        assert!(!is_legacy(db.as_ref()).unwrap());
+        assert!(BlockChainDb::needs_recover(db.as_ref()).unwrap());
+        // 旧的 key 都是 32 字节，只剩下出块时中间产生、任何 root 都不引用的 trie 节点
+        let all = dump(db.as_ref());
+        let left: Vec<_> = all.iter().filter(|(k, _)| k.len() == 32).collect();
+        assert_eq!(left.len(), 3);
+        assert!(left.iter().all(|(_, v)| trie::node_children(v).is_ok()));
+
+        let v = db.get(b"mtail").unwrap().unwrap();
+        assert_eq!(coder::deserialize::<[u8; 32]>(&v).unwrap(), tail);
+        assert_eq!(BlockChainDb::read_height(db.as_ref(), 1).unwrap(), Some(b1));
+        assert_eq!(
+            BlockChainDb::read_height(db.as_ref(), 2).unwrap(),
+            Some(tail)
+        );
+        assert_eq!(
+            BlockChainDb::read_tx_index(db.as_ref(), &tx).unwrap(),
+            Some((b1, 1))
+        );
+        let k = DbKey::new(Column::Block, &tail);
+        let b: Block = coder::deserialize(&db.get(k.as_bytes()).unwrap().unwrap()).unwrap();
+        assert_eq!(b.header.pre_hash, b1);
+        // 累计工作量和区块头在同一个 value 中
+        let k = DbKey::new(Column::Header, &tail);
+        let v = db.get(k.as_bytes()).unwrap().unwrap();
+        assert_eq!(wire::decode_header(&v[32..]).unwrap(), b.header);
+
+        // 状态树的节点可以从新的 key 读取
+        let state = StateDb::new(db, h);
+        let miner = ChainConfig::default().miner_address;
+        let root = b.header.state_root;
+        assert_eq!(state.get_account_at(&root, &miner).unwrap().balance, 100);
+        assert_eq!(state.get_account_at(&root, &alice).unwrap().nonce, 1);
+    }
+}
@@ -1,76 +1,213 @@
+use crate::core::account::Account;
 use crate::core::block::Block;
 use crate::core::blockchain::BlockChain;
+use crate::core::config::ChainConfig;
+use crate::core::mempool::Mempool;
 use crate::core::pow::ProofOfWork;
 use crate::core::transaction::Transaction;
+use crate::error::{Error, Result};
+use crate::utils::coder::Hasher;
+use chrono::Utc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
+/// clone 共享同一个中断标记
+#[derive(Clone)]
 pub struct Miner {
     address: [u8; 32],
+    max_nonce: u32,
+    threads: usize,
+    hasher: Hasher,
+    /// 设置后正在进行的 pow 停止，例如收到了同一高度的区块
+    cancel: Arc<AtomicBool>,
 }
 
 impl Miner {
-    pub fn new(address: [u8; 32]) -> Miner {
-        Miner { address }
+    /// 奖励地址、pow 参数和 hash 函数来自 config
+    pub fn new(config: &ChainConfig) -> Miner {
+        Miner {
+            address: config.miner_address,
+            max_nonce: config.max_nonce,
+            threads: config.miner_threads,
+            hasher: config.hasher,
+            cancel: Arc::new(AtomicBool::new(false)),
+        }
     }
 
-    fn produce_block(
-        vec_tx: Vec<Transaction>,
-        pre_hash: [u8; 32],
-        bits: u32,
-        height: u64,
-    ) -> Block {
-        let mut block = Block::new(vec_tx, pre_hash, bits, height);
-        let pow = ProofOfWork::new(bits);
-        pow.run(&mut block);
+    /// 其他线程通过它中断正在进行的 pow ，下一次 prepare 时清除
+    pub fn cancel_handle(&self) -> Arc<AtomicBool> {
+        self.cancel.clone()
+    }
 
-        block
+    /// 中断正在进行的 pow
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
     }
 
+    /// 在 chain 的 tail 之后出块，pow 被中断时返回 None
     pub fn mine(
         &self,
         transactions: &mut Vec<Transaction>,
-        pre_hash: [u8; 32],
-        bits: u32,
-        height: u64,
-    ) -> Block {
+        chain: &BlockChain,
+    ) -> Result<Option<Block>> {
+        let block = self.prepare(transactions, chain)?;
+        Ok(self.work(block))
+    }
+
+    /// 对 prepare 组装的区块做 pow ，不需要 chain ，调用者不用在 pow 期间锁住 Host
+    pub fn work(&self, mut block: Block) -> Option<Block> {
+        let mut pow = ProofOfWork::new(block.header.bits);
+        pow.set_max_nonce(self.max_nonce);
+        pow.set_threads(self.threads);
+        pow.run(&mut block, self.hasher, &self.cancel)?;
+
+        Some(block)
+    }
+
+    /// 在 chain 的 tail 之后组装还没有 pow 的区块，执行失败的交易（nonce 或余额不对）不打包
+    ///
+    /// coinbase 领取出块奖励和打包的交易的手续费
+    pub fn prepare(
+        &self,
+        transactions: &mut Vec<Transaction>,
+        chain: &BlockChain,
+    ) -> Result<Block> {
+        self.cancel.store(false, Ordering::Relaxed);
+        let height = chain.curr_height + 1;
         let mut vec_tx: Vec<Transaction> = Vec::new();
-        let tx = Transaction::new([0; 32], self.address, 0, 0, 0, "coinbase".to_string());
+        // coinbase 带上高度，不同区块的 coinbase hash 不同
+        let data = format!("coinbase {}", height);
+        // 金额在选完交易之后填写，coinbase 在最后执行，不影响交易的选择
+        let tx = Transaction::new_coinbase(self.address, 0, data.as_bytes(), self.hasher);
         vec_tx.push(tx);
-        vec_tx.append(transactions);
+        // 每笔交易只在之前选中的交易的结果上执行一次
+        let mut pending = chain.pending_state()?;
+        for tx in transactions.drain(..) {
+            match pending.add(&tx) {
+                Ok(()) => vec_tx.push(tx),
+                Err(Error::Validation(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
 
-        // really, should check the bits need modify
-        let bits = bits;
+        let fees = vec_tx
+            .iter()
+            .skip(1)
+            .fold(0u64, |sum, tx| sum.saturating_add(tx.fee));
+        let reward = chain.config().subsidy(height).saturating_add(fees);
+        vec_tx[0] = Transaction::new_coinbase(self.address, reward, data.as_bytes(), self.hasher);
 
-        Miner::produce_block(vec_tx, pre_hash, bits, height)
+        // pow 之前先算出执行交易后的 state_root
+        let state_root = pending.finish(&vec_tx[0])?;
+
+        let mut block = Block::new(
+            vec_tx,
+            chain.curr_hash,
+            chain.next_bits()?,
+            height,
+            self.hasher,
+        );
+        block.header.state_root = state_root;
+        Ok(block)
     }
 }
 
 pub struct Host {
     blockchain: BlockChain,
+    mempool: Mempool,
     miner: Miner,
 }
 
-const MINER_ADDRESS: [u8; 32] = [8; 32];
+/// 给区块头和 coinbase 预留的字节数，其余的留给交易池中的交易
+const BLOCK_RESERVED_SIZE: usize = 1024;
 
 impl Host {
-    pub fn new() -> Host {
+    /// 打开 config.data_dir 下的数据库
+    pub fn new(config: ChainConfig) -> Result<Host> {
+        Ok(Self::with_blockchain(BlockChain::open(config)?))
+    }
+
+    /// 矿工参数使用 blockchain 的 ChainConfig
+    pub fn with_blockchain(blockchain: BlockChain) -> Host {
         Host {
-            blockchain: BlockChain::new_blockchain(),
-            miner: Miner::new(MINER_ADDRESS),
+            miner: Miner::new(blockchain.config()),
+            blockchain,
+            mempool: Mempool::default(),
         }
     }
 
-    pub fn mining(&mut self, txs: &mut Vec<Transaction>) {
-        let b = self.miner.mine(
-            txs,
-            self.blockchain.curr_hash,
-            self.blockchain.curr_bits,
-            self.blockchain.curr_height + 1,
-        );
+    /// 验证后放入交易池，等待打包
+    pub fn add_transaction(&mut self, tx: Transaction) -> Result<()> {
+        let mut sender = self.blockchain.get_account(&tx.from)?;
+        // 还没有成熟的 coinbase 不能花费
+        let immature = self.blockchain.immature_balance(&tx.from)?;
+        sender.balance = sender.balance.saturating_sub(immature);
+        let hasher = self.blockchain.config().hasher;
+        self.mempool
+            .add(tx, &sender, Utc::now().timestamp(), hasher)?;
+        Ok(())
+    }
+
+    /// 从交易池中按手续费率选出交易出块
+    pub fn mining(&mut self) -> Result<()> {
+        let b = self.block_template()?;
+        match self.miner.work(b) {
+            Some(b) => self.input_block(b),
+            // 被中断，交易还在交易池中
+            None => Ok(()),
+        }
+    }
+
+    /// 从交易池中按手续费率选出交易，组装还没有 pow 的区块，pow 见 Miner::work
+    pub fn block_template(&mut self) -> Result<Block> {
+        self.mempool.evict(Utc::now().timestamp());
+        let chain = &self.blockchain;
+        let max_size = chain.config().max_block_size;
+        let mut txs = self.mempool.select(
+            |address| Ok(chain.get_account(address)?.nonce),
+            max_size.saturating_sub(BLOCK_RESERVED_SIZE),
+        )?;
+        self.miner.prepare(&mut txs, &self.blockchain)
+    }
+
+    /// 其他节点的区块，tail 变化时更新交易池
+    pub fn input_block(&mut self, b: Block) -> Result<()> {
+        self.blockchain.input_block(b)?;
+        self.update_mempool()
+    }
+
+    /// tail 变化后删除已经打包的交易，分叉切换后回滚的交易重新放回交易池
+    fn update_mempool(&mut self) -> Result<()> {
+        let chain = &self.blockchain;
+        self.mempool
+            .prune(|address| Ok(chain.get_account(address)?.nonce))?;
+        for tx in self.blockchain.take_orphan_txs() {
+            // 新链上已经不合法的交易直接丢弃
+            match self.add_transaction(tx) {
+                Ok(()) | Err(Error::Mempool(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn blockchain(&self) -> &BlockChain {
+        &self.blockchain
+    }
+
+    pub fn mempool(&self) -> &Mempool {
+        &self.mempool
+    }
+
+    pub fn miner(&self) -> &Miner {
+        &self.miner
+    }
 
-        self.blockchain.input_block(b).unwrap();
+    pub fn get_account(&self, address: &[u8; 32]) -> Result<Account> {
+        self.blockchain.get_account(address)
     }
 
-    pub fn print(&self) {
-        self.blockchain.print();
+    pub fn print(&self) -> Result<()> {
+        self.blockchain.print()
     }
 }
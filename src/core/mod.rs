@@ -1,7 +1,15 @@
-mod account;
-mod bcdb;
-mod block;
+pub mod account;
+pub mod bcdb;
+pub mod block;
 pub mod blockchain;
+pub mod config;
+pub mod mempool;
+pub mod migrate;
 pub mod miner;
 mod pow;
+pub mod state;
+pub mod storage;
 pub mod transaction;
+pub mod trie;
+pub mod validation;
+pub mod wire;
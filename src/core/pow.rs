@@ -18,18 +18,34 @@
 /// https://en.bitcoin.it/wiki/Difficulty
 /// 期望 10 minutes 一个block ，所以 2016 blocks 正好要两周，If the previous 2016 blocks took more than
 /// two weeks to find, the difficulty is reduced. If they took less than two weeks, the difficulty is increased.
+/// 挖矿时 nonce 空间分给多个线程搜索，全部失败后更新时间戳重新搜索，
+/// 收到新的区块时可以通过 cancel 标志中断。
 ///
-///
-///
-use crate::core::block::Block;
-use crate::utils::coder;
-use crate::utils::key::U256;
+use crate::core::block::{Block, BlockHeader};
+use crate::core::validation::MAX_FUTURE_BLOCK_TIME;
+use crate::utils::coder::Hasher;
+use crate::utils::key::{U256, U512};
+use chrono::Utc;
+use std::cmp;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 
-const MAX_NONCE: u32 = 0x7FFFFFFF;
+/// 默认的最大 nonce ，见 ChainConfig.max_nonce
+pub const MAX_NONCE: u32 = 0x7FFFFFFF;
+
+/// 默认每 RETARGET_INTERVAL 个区块调整一次难度（bitcoin 是 2016），见 ChainConfig.retarget_interval
+pub const RETARGET_INTERVAL: u64 = 20;
+/// 期望的出块时间，秒（bitcoin 是 10 minutes）
+pub const TARGET_BLOCK_TIME: i64 = 10;
+/// 一次调整最多 4 倍
+const MAX_ADJUST_FACTOR: i64 = 4;
 
 pub struct ProofOfWork {
     /// target is a 256 bit number
     /// - difficulty = difficulty_1_target / current_target
+    ///
     /// 这里target直接取 difficulty_1_target
     ///
     /// bdiff : difficulty_1_target : 0x1d00ffff
@@ -37,63 +53,291 @@ pub struct ProofOfWork {
     /// pdiff : difficulty_1_target
     /// 0x00000000FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF
     target: U256,
+    max_nonce: u32,
+    /// 搜索 nonce 的线程数，0 表示 CPU 核数
+    threads: usize,
 }
 
 impl ProofOfWork {
     /// # Arguments
-    /// * bits - BlockChain.curr_bits
+    /// * bits - BlockChain.next_bits()
+    ///
     /// https://en.bitcoin.it/wiki/Difficulty
     ///
     /// The compact format of target is 特殊的 floating-point encoding using 24 bits mantissa,
     /// the first 8 bits are exponent (where only the 5 lowest bits are used) and its base is 256.
     /// - 0x1b0404cb :
+    ///
     /// 0x0404cb * 2**(8*(0x1b - 3)) = 0x00000000000404CB000000000000000000000000000000000000000000000000
     ///
     pub fn new(bits: u32) -> ProofOfWork {
+        ProofOfWork {
+            target: Self::bits_to_target(bits),
+            max_nonce: MAX_NONCE,
+            threads: 1,
+        }
+    }
+
+    pub fn set_max_nonce(&mut self, max_nonce: u32) {
+        self.max_nonce = max_nonce;
+    }
+
+    /// 0 表示 CPU 核数
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads;
+    }
+
+    /// 负数和超过 256 位的编码返回 0 ，与 bitcoin 的 SetCompact 的 fNegative / fOverflow 相同，
+    /// 这样的 bits 没有任何 hash 能满足，ChainConfig::validate 拒绝它们作为 initial_bits
+    pub fn bits_to_target(bits: u32) -> U256 {
         let mantissa = bits & 0xFFFFFF;
         // mantissa contains a sign bit in the 24th bit
         // so the largest value for mantissa is 0x7fffff , and the smallest value is 0x800000 （wiki上写错了）
         if mantissa > 0x7FFFFF {
-            return ProofOfWork {
-                target: Default::default(),
-            };
+            return Default::default();
         }
 
         let exponent = (bits >> 24) as usize;
+        // 左移后超过 256 位，截断会得到错误的 target
+        let overflow = mantissa != 0
+            && (exponent > 34
+                || (mantissa > 0xFF && exponent > 33)
+                || (mantissa > 0xFFFF && exponent > 32));
+        if overflow {
+            return Default::default();
+        }
         if exponent < 3 {
-            ProofOfWork {
-                target: U256::from(mantissa as u64) >> (8 * (3 - exponent)),
-            }
+            U256::from(mantissa as u64) >> (8 * (3 - exponent))
         } else {
-            ProofOfWork {
-                target: U256::from(mantissa as u64) << (8 * (exponent - 3)),
-            }
+            U256::from(mantissa as u64) << (8 * (exponent - 3))
         }
     }
 
-    pub fn block_header_se(b: &mut Block, nonce: u32) -> Vec<u8> {
-        b.header.nonce = nonce;
-        coder::serialize(&b.header)
+    /// bits_to_target 的逆运算，精度只保留 mantissa 的 3 个字节
+    pub fn target_to_bits(target: U256) -> u32 {
+        let mut size = target.bits().div_ceil(8);
+        let mut mantissa = if size <= 3 {
+            target.low_u64() << (8 * (3 - size))
+        } else {
+            (target >> (8 * (size - 3))).low_u64()
+        };
+        // 最高位是符号位，不能为 1
+        if mantissa & 0x00800000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+
+        (mantissa as u32) | ((size as u32) << 24)
+    }
+
+    /// 难度调整
+    /// new_target = old_target * actual_timespan / target_timespan
+    ///
+    /// # Arguments
+    /// * bits - 上一个区块的 bits
+    /// * actual_timespan - 这 interval 个区块实际花费的时间
+    /// * interval - 调整难度的区块间隔
+    /// * limit_bits - 最低难度，target 不能超过它
+    pub fn retarget(bits: u32, actual_timespan: i64, interval: u64, limit_bits: u32) -> u32 {
+        let target_timespan = interval as i64 * TARGET_BLOCK_TIME;
+        let actual_timespan = actual_timespan.clamp(
+            target_timespan / MAX_ADJUST_FACTOR,
+            target_timespan * MAX_ADJUST_FACTOR,
+        );
+
+        // target 接近 2^256 ，乘法用 U512
+        let mut buf = [0u8; 64];
+        Self::bits_to_target(bits).to_big_endian(&mut buf[32..]);
+        let new = U512::from_big_endian(&buf) * U512::from(actual_timespan as u64)
+            / U512::from(target_timespan as u64);
+
+        Self::bits_to_target(limit_bits).to_big_endian(&mut buf[32..]);
+        if new > U512::from_big_endian(&buf) {
+            return limit_bits;
+        }
+        new.to_big_endian(&mut buf);
+        Self::target_to_bits(U256::from_big_endian(&buf[32..]))
+    }
+
+    /// 找到一个满足 bits 的 hash 期望的尝试次数：2^256 / (target + 1)
+    /// 分叉时选择累计工作量最大的链
+    pub fn work(bits: u32) -> U256 {
+        let target = Self::bits_to_target(bits);
+        if target.is_zero() {
+            return U256::zero();
+        }
+        // 2^256 超出 U256 ，等价于 (2^256 - target - 1) / (target + 1) + 1
+        (!target / (target + U256::one())) + U256::one()
+    }
+
+    /// hash 是否满足 target
+    pub fn check(&self, hash: &[u8; 32]) -> bool {
+        U256::from(hash) <= self.target
     }
 
     /// expensive task
-    pub fn run(&self, b: &mut Block) {
-        let mut nonce = 0u32;
-        while nonce <= MAX_NONCE {
-            let data = Self::block_header_se(b, nonce);
-            // 应该要双重SHA256运算（即SHA256(SHA256(Block_Header))）
-            let mut hash = coder::get_hash(&data);
-
-            let hash_uint = U256::from(hash);
-            // 计算成功
-            if hash_uint <= self.target {
+    ///
+    /// 成功时 nonce、time 和 hash 写入 b 并返回 hash ，cancel 被设置时返回 None
+    /// hasher 是链的 hash 函数，ChainConfig.hasher 为 double_sha256 时与 bitcoin 相同
+    pub fn run(&self, b: &mut Block, hasher: Hasher, cancel: &AtomicBool) -> Option<[u8; 32]> {
+        let threads = match self.threads {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        loop {
+            if let Some((nonce, hash)) = self.search(&b.header, hasher, threads, cancel) {
                 println!("pow success, hash:  {:?}", hash);
+                b.header.nonce = nonce;
                 b.hash = hash;
+                return Some(hash);
+            }
+            if cancel.load(Ordering::Relaxed) {
+                return None;
+            }
 
-                break;
-            } else {
-                nonce += 1;
+            // nonce 用完了，换一个时间戳，但不能超过验证允许的范围
+            let now = Utc::now().timestamp();
+            if b.header.time >= now + MAX_FUTURE_BLOCK_TIME {
+                thread::sleep(Duration::from_secs(1));
             }
+            b.header.time = cmp::max(b.header.time + 1, now);
+        }
+    }
+
+    /// 在 [0, max_nonce] 中搜索，线程 i 尝试 i, i + threads, i + 2 * threads ...
+    fn search(
+        &self,
+        header: &BlockHeader,
+        hasher: Hasher,
+        threads: usize,
+        cancel: &AtomicBool,
+    ) -> Option<(u32, [u8; 32])> {
+        let found = Mutex::new(None);
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            for i in 0..threads {
+                let (found, done) = (&found, &done);
+                let mut header = header.clone();
+                s.spawn(move || {
+                    for nonce in (i as u64..=self.max_nonce as u64).step_by(threads) {
+                        if done.load(Ordering::Relaxed) || cancel.load(Ordering::Relaxed) {
+                            return;
+                        }
+                        header.nonce = nonce as u32;
+                        let hash = header.hash(hasher);
+                        if self.check(&hash) {
+                            done.store(true, Ordering::Relaxed);
+                            if let Ok(mut found) = found.lock() {
+                                found.get_or_insert((header.nonce, hash));
+                            }
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        found.into_inner().ok().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ProofOfWork, RETARGET_INTERVAL, TARGET_BLOCK_TIME};
+    use crate::core::block::Block;
+    use crate::core::transaction::Transaction;
+    use crate::utils::coder::Hasher;
+    use crate::utils::hex;
+    use crate::utils::key::U256;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn run_works() {
+        let h = Hasher::default();
+        let coinbase = Transaction::new_coinbase([8; 32], 0, b"coinbase", h);
+        let bits = 0x2000ffff;
+        let mut pow = ProofOfWork::new(bits);
+        pow.set_threads(4);
+        // nonce 空间很小，需要更新时间戳
+        pow.set_max_nonce(3);
+
+        let mut b = Block::new(vec![coinbase], [0; 32], bits, 1, h);
+        let hash = pow.run(&mut b, h, &AtomicBool::new(false)).unwrap();
+        assert_eq!(b.hash, hash);
+        assert_eq!(b.header.hash(h), hash);
+        assert!(b.header.nonce <= 3);
+        assert!(pow.check(&hash));
+
+        let mut b = Block::new(vec![], [0; 32], bits, 1, h);
+        assert_eq!(pow.run(&mut b, h, &AtomicBool::new(true)), None);
+        assert_eq!(b.hash, [0; 32]);
+    }
+
+    /// bitcoin genesis 的 80 字节区块头
+    #[test]
+    fn bitcoin_header_works() {
+        let header = hex::decode(
+            "01000000000000000000000000000000000000000000000000000000000000000000000\
+             03ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49\
+             ffff001d1dac2b7c",
+        )
+        .unwrap();
+        let mut hash = Hasher::DoubleSha256.hash(&header);
+        // bitcoin 显示 hash 时按小端倒序
+        hash.reverse();
+        assert_eq!(
+            hex::encode(&hash),
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+        assert!(ProofOfWork::new(0x1d00ffff).check(&hash));
+    }
+
+    #[test]
+    fn compact_bits_works() {
+        for bits in [0x1d00ffff, 0x1b0404cb, 0x2100ffff, 0x03123456, 0x05009234] {
+            let target = ProofOfWork::bits_to_target(bits);
+            assert_eq!(ProofOfWork::target_to_bits(target), bits);
         }
+
+        // 负数
+        assert!(ProofOfWork::bits_to_target(0x1d80ffff).is_zero());
+        assert!(ProofOfWork::bits_to_target(0x04923456).is_zero());
+        // 超过 256 位
+        assert!(ProofOfWork::bits_to_target(0x23000001).is_zero());
+        assert!(ProofOfWork::bits_to_target(0x220001ff).is_zero());
+        assert!(ProofOfWork::bits_to_target(0x2101ffff).is_zero());
+        assert!(ProofOfWork::bits_to_target(0xff123456).is_zero());
+        // 刚好 256 位
+        assert_eq!(
+            ProofOfWork::bits_to_target(0x2100ffff),
+            U256::from(0xffff) << 240
+        );
+        assert_eq!(ProofOfWork::bits_to_target(0x22000001), U256::one() << 248);
+        assert_eq!(ProofOfWork::work(0x2101ffff), U256::zero());
+    }
+
+    #[test]
+    fn retarget_works() {
+        let interval = RETARGET_INTERVAL;
+        let timespan = interval as i64 * TARGET_BLOCK_TIME;
+        let limit = 0x2100ffff;
+        let bits = 0x1d00ffff;
+
+        assert_eq!(ProofOfWork::retarget(bits, timespan, interval, limit), bits);
+        // 快了一倍，target 减半
+        assert_eq!(
+            ProofOfWork::retarget(bits, timespan / 2, interval, limit),
+            0x1c7fff80
+        );
+        // 最多 4 倍
+        assert_eq!(
+            ProofOfWork::retarget(bits, 0, interval, limit),
+            ProofOfWork::retarget(bits, timespan / 4, interval, limit)
+        );
+        // 不能低于最低难度
+        assert_eq!(
+            ProofOfWork::retarget(limit, timespan * 10, interval, limit),
+            limit
+        );
     }
 }
@@ -0,0 +1,328 @@
+/// 世界状态：address -> Account
+///
+/// 账户存在 Merkle Patricia Trie 中（见 trie.rs），trie 的节点存在 Storage 中，
+/// 区块中的交易按顺序执行：
+/// - 转账：from 扣除 amount + fee ，nonce + 1 ；to 增加 amount
+/// - coinbase：to 增加 amount（出块奖励 + 整个区块的 fee ，见 validation::check_coinbase）
+///
+/// coinbase 的金额计入余额，但在 ChainConfig.coinbase_maturity 个区块之内不能花费。
+///
+/// 执行后 trie 的 root 就是 BlockHeader.state_root ，节点不可变，
+/// 所以每个区块的 state_root 都可以用来查询当时的账户和生成证明。
+///
+use crate::core::account::Account;
+use crate::core::bcdb::BlockChainDb;
+use crate::core::block::Block;
+use crate::core::storage::{Storage, WriteBatch};
+use crate::core::transaction::Transaction;
+use crate::core::trie::{self, Trie, TrieDb, EMPTY_ROOT};
+use crate::core::validation::BlockError;
+use crate::error::{Error, Result};
+use crate::utils::coder::{self, Hasher};
+use crate::utils::key::{Column, DbKey};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub struct StateDb {
+    /// 与区块共用一个数据库，trie 节点和区块在同一个 batch 中写入
+    db: Arc<dyn Storage>,
+    /// 当前状态的 root
+    root: [u8; 32],
+    /// trie 节点和账户的 hash 函数，见 ChainConfig.hasher
+    hasher: Hasher,
+}
+
+/// 执行交易后改变的账户，还没有写入数据库
+pub type StateChanges = HashMap<[u8; 32], Account>;
+
+/// address -> 还没有成熟、不能花费的 coinbase 金额
+pub type ImmatureFunds = HashMap<[u8; 32], u64>;
+
+impl StateDb {
+    pub fn new(db: Arc<dyn Storage>, hasher: Hasher) -> StateDb {
+        StateDb {
+            db,
+            root: EMPTY_ROOT,
+            hasher,
+        }
+    }
+
+    pub fn root(&self) -> [u8; 32] {
+        self.root
+    }
+
+    /// 切换到某个区块的状态，回滚和重放都只需要切换 root
+    pub fn set_root(&mut self, root: [u8; 32]) {
+        self.root = root;
+    }
+
+    /// 不存在的账户余额为 0
+    pub fn get_account(&self, address: &[u8; 32]) -> Result<Account> {
+        self.get_account_at(&self.root, address)
+    }
+
+    /// root 时刻的账户
+    pub fn get_account_at(&self, root: &[u8; 32], address: &[u8; 32]) -> Result<Account> {
+        match Trie::new(&self.db, *root, self.hasher).get(address)? {
+            Some(v) => coder::deserialize(&v),
+            None => Ok(Account::with_address(*address)),
+        }
+    }
+
+    fn get_changed(
+        &self,
+        root: &[u8; 32],
+        changes: &StateChanges,
+        address: &[u8; 32],
+    ) -> Result<Account> {
+        match changes.get(address) {
+            Some(account) => Ok(account.clone()),
+            None => self.get_account_at(root, address),
+        }
+    }
+
+    /// 在 root 状态上按顺序执行交易，第一笔为 coinbase ，不写数据库
+    ///
+    /// # Arguments
+    /// * immature - 父区块之前还没有成熟的 coinbase ，这部分余额不能花费
+    pub fn execute(
+        &self,
+        root: &[u8; 32],
+        txs: &[Transaction],
+        immature: &ImmatureFunds,
+    ) -> Result<StateChanges> {
+        let mut changes = StateChanges::new();
+
+        for (i, tx) in txs.iter().enumerate().skip(1) {
+            self.execute_tx(root, &mut changes, i, tx, immature)?;
+        }
+        if let Some(coinbase) = txs.first() {
+            self.execute_coinbase(root, &mut changes, coinbase)?;
+        }
+
+        Ok(changes)
+    }
+
+    /// 执行区块中第 i 笔转账，失败时 changes 不变
+    fn execute_tx(
+        &self,
+        root: &[u8; 32],
+        changes: &mut StateChanges,
+        i: usize,
+        tx: &Transaction,
+        immature: &ImmatureFunds,
+    ) -> Result<()> {
+        let mut from = self.get_changed(root, changes, &tx.from)?;
+        if tx.nonce != from.nonce + 1 {
+            return Err(BlockError::BadNonce(i).into());
+        }
+        let locked = immature.get(&tx.from).copied().unwrap_or(0);
+        match tx.amount.checked_add(tx.fee) {
+            Some(cost) if cost <= from.balance.saturating_sub(locked) => from.balance -= cost,
+            Some(cost) if cost <= from.balance => {
+                return Err(BlockError::ImmatureCoinbase(i).into())
+            }
+            _ => return Err(BlockError::InsufficientBalance(i).into()),
+        }
+        from.nonce += 1;
+        from.set_hash(self.hasher);
+
+        // 转给自己时 to 是扣款之后的 from
+        let mut to = if tx.to == tx.from {
+            from.clone()
+        } else {
+            self.get_changed(root, changes, &tx.to)?
+        };
+        to.balance = to
+            .balance
+            .checked_add(tx.amount)
+            .ok_or(BlockError::BalanceOverflow(i))?;
+        to.set_hash(self.hasher);
+        changes.insert(tx.from, from);
+        changes.insert(tx.to, to);
+
+        Ok(())
+    }
+
+    /// coinbase 在区块中的转账之后执行
+    fn execute_coinbase(
+        &self,
+        root: &[u8; 32],
+        changes: &mut StateChanges,
+        coinbase: &Transaction,
+    ) -> Result<()> {
+        let mut miner = self.get_changed(root, changes, &coinbase.to)?;
+        miner.balance = miner
+            .balance
+            .checked_add(coinbase.amount)
+            .ok_or(BlockError::BalanceOverflow(0))?;
+        miner.set_hash(self.hasher);
+        changes.insert(coinbase.to, miner);
+
+        Ok(())
+    }
+
+    fn trie_with(&self, root: &[u8; 32], changes: &StateChanges) -> Result<Trie<'_>> {
+        let mut trie = Trie::new(&self.db, *root, self.hasher);
+        for (address, account) in changes {
+            trie.insert(address, coder::serialize(account))?;
+        }
+        Ok(trie)
+    }
+
+    /// root 加上 changes 之后的 state_root
+    pub fn root_with(&self, root: &[u8; 32], changes: &StateChanges) -> Result<[u8; 32]> {
+        Ok(self.trie_with(root, changes)?.root())
+    }
+
+    /// root 的 trie 节点是否已经写入
+    pub fn has_root(&self, root: &[u8; 32]) -> Result<bool> {
+        Ok(*root == EMPTY_ROOT || self.db.get_node(root)?.is_some())
+    }
+
+    /// 新节点放进 batch ，返回新的 root ，当前状态不变
+    pub fn commit(
+        &self,
+        root: &[u8; 32],
+        changes: StateChanges,
+        batch: &mut WriteBatch,
+    ) -> Result<[u8; 32]> {
+        let trie = self.trie_with(root, &changes)?;
+        let root = trie.root();
+        let nodes = trie.into_pending();
+
+        for (hash, data) in nodes {
+            BlockChainDb::write_db(batch, DbKey::new(Column::State, &hash), &data);
+        }
+
+        Ok(root)
+    }
+
+    /// 在父区块的状态上执行区块中的交易，state_root 必须与区块头一致
+    /// 只把 trie 节点放进 batch ，不改变当前状态，分叉上的区块也可以执行
+    pub fn apply_block(
+        &self,
+        parent_root: &[u8; 32],
+        b: &Block,
+        immature: &ImmatureFunds,
+        batch: &mut WriteBatch,
+    ) -> Result<()> {
+        let changes = self.execute(parent_root, &b.transactions, immature)?;
+        if self.root_with(parent_root, &changes)? != b.header.state_root {
+            return Err(BlockError::BadStateRoot.into());
+        }
+        self.commit(parent_root, changes, batch)?;
+
+        Ok(())
+    }
+
+    /// root 时刻 address 的证明
+    pub fn prove(&self, root: &[u8; 32], address: &[u8; 32]) -> Result<Vec<Vec<u8>>> {
+        Trie::new(&self.db, *root, self.hasher).prove(address)
+    }
+}
+
+/// 出块时在 root 状态上逐笔加入交易，每笔只执行一次，最后加上 coinbase 计算 state_root
+pub struct PendingState<'a> {
+    state: &'a StateDb,
+    root: [u8; 32],
+    immature: ImmatureFunds,
+    changes: StateChanges,
+    /// 已经加入的交易数，包括 coinbase 的位置
+    count: usize,
+}
+
+impl<'a> PendingState<'a> {
+    pub fn new(state: &'a StateDb, root: [u8; 32], immature: ImmatureFunds) -> PendingState<'a> {
+        PendingState {
+            state,
+            root,
+            immature,
+            changes: StateChanges::new(),
+            count: 1,
+        }
+    }
+
+    /// 执行失败时不加入，状态不变
+    pub fn add(&mut self, tx: &Transaction) -> Result<()> {
+        self.state.execute_tx(
+            &self.root,
+            &mut self.changes,
+            self.count,
+            tx,
+            &self.immature,
+        )?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// 执行 coinbase 之后的 state_root
+    pub fn finish(mut self, coinbase: &Transaction) -> Result<[u8; 32]> {
+        self.state
+            .execute_coinbase(&self.root, &mut self.changes, coinbase)?;
+        self.state.root_with(&self.root, &self.changes)
+    }
+}
+
+/// 轻节点只需要区块头的 state_root 就能验证账户余额，hasher 是链的 hash 函数
+pub fn verify_account_proof(
+    state_root: &[u8; 32],
+    address: &[u8; 32],
+    proof: &[Vec<u8>],
+    hasher: Hasher,
+) -> Result<Account> {
+    match trie::verify_proof(state_root, address, proof, hasher)? {
+        Some(v) => coder::deserialize(&v).map_err(|e| Error::Proof(e.to_string())),
+        None => Ok(Account::with_address(*address)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ImmatureFunds, StateDb};
+    use crate::core::account::Account;
+    use crate::core::storage::{MemoryStorage, Storage, WriteBatch};
+    use crate::core::transaction::Transaction;
+    use crate::core::trie::EMPTY_ROOT;
+    use crate::core::validation::BlockError;
+    use crate::error::Error;
+    use crate::utils::coder::Hasher;
+    use std::sync::Arc;
+
+    #[test]
+    fn balance_overflow_works() {
+        let h = Hasher::default();
+        let db: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let state = StateDb::new(db.clone(), h);
+        let immature = ImmatureFunds::new();
+        let mut alice = Account::generate();
+        let bob = [2; 32];
+
+        // alice 有 1 ，bob 有 u64::MAX
+        let txs = [
+            Transaction::new_coinbase(bob, u64::MAX, b"a", h),
+            Transaction::new_coinbase(alice.address, 1, b"b", h),
+        ];
+        let mut root = EMPTY_ROOT;
+        for tx in txs {
+            let changes = state.execute(&root, &[tx], &immature).unwrap();
+            let mut batch = WriteBatch::new();
+            root = state.commit(&root, changes, &mut batch).unwrap();
+            db.write(batch).unwrap();
+        }
+
+        let coinbase = Transaction::new_coinbase(bob, 1, b"c", h);
+        assert_eq!(
+            state.execute(&root, &[coinbase], &immature),
+            Err(Error::Validation(BlockError::BalanceOverflow(0)))
+        );
+
+        alice.balance = 1;
+        let tx = alice.send_to(bob, 1, 0, h).unwrap();
+        let coinbase = Transaction::new_coinbase([3; 32], 0, b"d", h);
+        assert_eq!(
+            state.execute(&root, &[coinbase, tx], &immature),
+            Err(Error::Validation(BlockError::BalanceOverflow(1)))
+        );
+    }
+}
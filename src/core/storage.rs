@@ -0,0 +1,341 @@
+/// 键值存储后端
+///
+/// - LevelDbStorage : 默认，feature "leveldb"
+/// - RocksDbStorage : feature "rocksdb"
+/// - MemoryStorage : 纯内存，用于测试
+///
+/// 两个 feature 都打开时 open 使用 RocksDB ，都不打开时 open 返回错误
+///
+use crate::error::{Error, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+pub enum BatchOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+/// 一组原子写入的操作
+#[derive(Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+    /// 写入后等待数据落盘
+    sync: bool,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch {
+            ops: Vec::new(),
+            sync: false,
+        }
+    }
+
+    pub fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.ops.push(BatchOp::Put(key.to_vec(), value.to_vec()));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.ops.push(BatchOp::Delete(key.to_vec()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+}
+
+/// 多个线程共享同一个数据库，写入只需要 &self
+pub trait Storage: Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        self.write(batch)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(batch)
+    }
+
+    /// batch 中的操作要么全部写入，要么都不写入
+    fn write(&self, batch: WriteBatch) -> Result<()>;
+
+    /// key 以 prefix 开头的所有数据，按 key 排序
+    fn iter_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+}
+
+/// 打开 path 下的数据库
+pub fn open(path: &Path) -> Result<Arc<dyn Storage>> {
+    #[cfg(feature = "rocksdb")]
+    return Ok(Arc::new(RocksDbStorage::open(path)?));
+
+    #[cfg(all(feature = "leveldb", not(feature = "rocksdb")))]
+    return Ok(Arc::new(LevelDbStorage::open(path)?));
+
+    // MemoryStorage 退出后丢失所有数据，只用于测试
+    #[cfg(not(any(feature = "leveldb", feature = "rocksdb")))]
+    {
+        let _ = path;
+        Err(Error::Storage("no storage backend enabled".to_string()))
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    map: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage {
+            map: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn map(&self) -> Result<MutexGuard<'_, BTreeMap<Vec<u8>, Vec<u8>>>> {
+        self.map
+            .lock()
+            .map_err(|_| Error::Storage("memory storage lock poisoned".to_string()))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.map()?.get(key).cloned())
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        let mut map = self.map()?;
+        for op in batch.ops {
+            match op {
+                BatchOp::Put(k, v) => {
+                    map.insert(k, v);
+                }
+                BatchOp::Delete(k) => {
+                    map.remove(&k);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn iter_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self
+            .map()?
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+}
+
+#[cfg(feature = "leveldb")]
+pub use self::level::LevelDbStorage;
+
+#[cfg(feature = "leveldb")]
+mod level {
+    use super::{BatchOp, Storage, WriteBatch};
+    use crate::error::{Error, Result};
+    use db_key::Key;
+    use leveldb::batch::{Batch, Writebatch};
+    use leveldb::database::Database;
+    use leveldb::iterator::{Iterable, LevelDBIterator};
+    use leveldb::kv::KV;
+    use leveldb::options::{Options, ReadOptions, WriteOptions};
+    use std::path::Path;
+
+    /// 任意长度的 key
+    pub struct BytesKey(Vec<u8>);
+
+    impl Key for BytesKey {
+        fn from_u8(key: &[u8]) -> Self {
+            BytesKey(key.to_vec())
+        }
+
+        fn as_slice<T, F>(&self, f: F) -> T
+        where
+            F: Fn(&[u8]) -> T,
+        {
+            f(&self.0)
+        }
+    }
+
+    pub struct LevelDbStorage {
+        db: Database<BytesKey>,
+    }
+
+    impl LevelDbStorage {
+        pub fn open(path: &Path) -> Result<LevelDbStorage> {
+            let mut options = Options::new();
+            options.create_if_missing = true;
+            let db = Database::open(path, options)
+                .map_err(|e| Error::Storage(format!("failed to open database: {}", e)))?;
+
+            Ok(LevelDbStorage { db })
+        }
+    }
+
+    impl Storage for LevelDbStorage {
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            let opts = ReadOptions::new();
+            self.db
+                .get(opts, BytesKey(key.to_vec()))
+                .map_err(|e| Error::Storage(format!("failed to read from database: {}", e)))
+        }
+
+        fn write(&self, batch: WriteBatch) -> Result<()> {
+            let mut wb = Writebatch::new();
+            for op in batch.ops() {
+                match op {
+                    BatchOp::Put(k, v) => wb.put(BytesKey(k.clone()), v),
+                    BatchOp::Delete(k) => wb.delete(BytesKey(k.clone())),
+                }
+            }
+            let mut opts = WriteOptions::new();
+            opts.sync = batch.sync;
+            self.db
+                .write(opts, &wb)
+                .map_err(|e| Error::Storage(format!("failed to write to database: {}", e)))
+        }
+
+        fn iter_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+            let from = BytesKey(prefix.to_vec());
+            Ok(self
+                .db
+                .iter(ReadOptions::new())
+                .from(&from)
+                .map(|(k, v)| (k.0, v))
+                .take_while(|(k, _)| k.starts_with(prefix))
+                .collect())
+        }
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+pub use self::rocks::RocksDbStorage;
+
+#[cfg(feature = "rocksdb")]
+mod rocks {
+    use super::{BatchOp, Storage, WriteBatch};
+    use crate::error::{Error, Result};
+    use rocksdb::{Direction, IteratorMode, WriteOptions, DB};
+    use std::path::Path;
+
+    pub struct RocksDbStorage {
+        db: DB,
+    }
+
+    impl RocksDbStorage {
+        pub fn open(path: &Path) -> Result<RocksDbStorage> {
+            let db = DB::open_default(path)
+                .map_err(|e| Error::Storage(format!("failed to open database: {}", e)))?;
+
+            Ok(RocksDbStorage { db })
+        }
+    }
+
+    impl Storage for RocksDbStorage {
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            self.db
+                .get(key)
+                .map_err(|e| Error::Storage(format!("failed to read from database: {}", e)))
+        }
+
+        fn write(&self, batch: WriteBatch) -> Result<()> {
+            let mut wb = rocksdb::WriteBatch::default();
+            for op in batch.ops() {
+                match op {
+                    BatchOp::Put(k, v) => wb.put(k, v),
+                    BatchOp::Delete(k) => wb.delete(k),
+                }
+            }
+            let mut opts = WriteOptions::default();
+            opts.set_sync(batch.sync);
+            self.db
+                .write_opt(wb, &opts)
+                .map_err(|e| Error::Storage(format!("failed to write to database: {}", e)))
+        }
+
+        fn iter_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+            let mut result = Vec::new();
+            for item in self
+                .db
+                .iterator(IteratorMode::From(prefix, Direction::Forward))
+            {
+                let (k, v) = item
+                    .map_err(|e| Error::Storage(format!("failed to read from database: {}", e)))?;
+                if !k.starts_with(prefix) {
+                    break;
+                }
+                result.push((k.to_vec(), v.to_vec()));
+            }
+            Ok(result)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryStorage, Storage, WriteBatch};
+
+    /// 所有后端的行为必须相同
+    fn check_storage(db: &dyn Storage) {
+        db.put(b"a1", b"1").unwrap();
+        db.put(b"b1", b"2").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"a3", b"4");
+        batch.put(b"a2", b"3");
+        batch.put(b"c", b"5");
+        batch.delete(b"a1");
+        batch.set_sync(true);
+        db.write(batch).unwrap();
+
+        assert_eq!(db.get(b"a1").unwrap(), None);
+        assert_eq!(db.get(b"b1").unwrap(), Some(b"2".to_vec()));
+        let keys = |prefix: &[u8]| -> Vec<Vec<u8>> {
+            db.iter_prefix(prefix)
+                .unwrap()
+                .into_iter()
+                .map(|(k, _)| k)
+                .collect()
+        };
+        // 按 key 排序，不包括 prefix 之后的其他 key
+        assert_eq!(keys(b"a"), vec![b"a2".to_vec(), b"a3".to_vec()]);
+        // prefix 是最后一个 key
+        assert_eq!(keys(b"c"), vec![b"c".to_vec()]);
+        assert!(keys(b"d").is_empty());
+        assert_eq!(keys(b"").len(), 4);
+
+        db.delete(b"b1").unwrap();
+        assert!(keys(b"b").is_empty());
+    }
+
+    #[test]
+    fn memory_storage_works() {
+        check_storage(&MemoryStorage::new());
+    }
+
+    #[cfg(feature = "leveldb")]
+    #[test]
+    fn leveldb_storage_works() {
+        use super::LevelDbStorage;
+        use std::fs;
+
+        let dir = std::env::temp_dir().join(format!("leveldb_storage_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        check_storage(&LevelDbStorage::open(&dir).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
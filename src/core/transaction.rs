@@ -1,4 +1,7 @@
-use crate::utils::coder;
+use crate::core::wire;
+use crate::error::{Error, Result};
+use crate::utils::coder::Hasher;
+use crate::utils::keypair::{self, KeyPair};
 use serde::{Deserialize, Serialize};
 
 /// 交易记录
@@ -13,17 +16,21 @@ pub struct Transaction {
     pub fee: u64,
     /// Account.nonce
     pub nonce: u64,
-    pub sign: String,
+    /// from 私钥对 sign_data() 的签名
+    /// coinbase 不需要签名，这里存放任意数据（类似 bitcoin coinbase 的 scriptSig）
+    pub sign: Vec<u8>,
 }
 
 impl Transaction {
+    /// 未签名的交易，转账交易需要再调用 sign()
+    /// hasher 是链的 hash 函数，见 ChainConfig.hasher
     pub fn new(
         from: [u8; 32],
         to: [u8; 32],
         amount: u64,
         fee: u64,
         nonce: u64,
-        sign: String,
+        hasher: Hasher,
     ) -> Self {
         let mut tx = Transaction {
             // set_hash
@@ -33,22 +40,127 @@ impl Transaction {
             amount,
             fee,
             nonce,
-            sign,
+            sign: Vec::new(),
         };
-        tx.set_hash();
+        tx.set_hash(hasher);
 
         tx
     }
 
-    pub fn set_hash(&mut self) {
-        let tx = coder::serialize(&self);
-        let mut hash = coder::get_hash(&tx);
+    /// 矿工奖励，data 写入 sign 字段，参与 hash 计算
+    pub fn new_coinbase(to: [u8; 32], amount: u64, data: &[u8], hasher: Hasher) -> Self {
+        let mut tx = Transaction {
+            hash: [0; 32],
+            from: [0; 32],
+            to,
+            amount,
+            fee: 0,
+            nonce: 0,
+            sign: data.to_vec(),
+        };
+        tx.set_hash(hasher);
+
+        tx
+    }
+
+    /// 对 payload() 计算，不包括 hash 和签名，签名前后 hash 不变
+    pub fn set_hash(&mut self, hasher: Hasher) {
+        let hash = hasher.hash(&self.payload());
 
         self.hash = hash;
     }
 
+    /// hash 是否与内容一致，从数据库或网络读取的交易需要检查
+    pub fn verify_hash(&self, hasher: Hasher) -> bool {
+        self.hash == hasher.hash(&self.payload())
+    }
+
+    /// 包括签名的 hash ，对 wire 编码计算，是区块 merkle 树的叶子
+    /// hash 不包括签名，替换签名后 hash 不变，区块头通过 witness_hash 确定签名
+    pub fn witness_hash(&self, hasher: Hasher) -> [u8; 32] {
+        hasher.hash(&wire::encode_tx(self))
+    }
+
+    /// 不包括 hash 和签名的内容，见 wire::encode_tx_payload
+    pub fn payload(&self) -> Vec<u8> {
+        wire::encode_tx_payload(self)
+    }
+
+    /// 被签名的内容，即 payload()
+    pub fn sign_data(&self) -> Vec<u8> {
+        self.payload()
+    }
+
+    /// 只有 from 对应的私钥才能签名
+    pub fn sign(&mut self, keypair: &KeyPair) -> Result<()> {
+        if keypair.address() != self.from {
+            return Err(Error::Account("keypair does not match tx.from".to_string()));
+        }
+
+        self.sign = keypair.sign(&self.sign_data());
+        Ok(())
+    }
+
+    /// 用 from 地址（公钥）验证签名，coinbase 没有签名
+    pub fn verify(&self) -> bool {
+        if self.is_coinbase() {
+            return true;
+        }
+
+        keypair::verify(&self.from, &self.sign_data(), &self.sign)
+    }
+
     /// coinbase
     pub fn is_coinbase(&self) -> bool {
         (self.from == [0; 32]) && (self.to != [0; 32])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Transaction;
+    use crate::utils::coder::Hasher;
+    use crate::utils::keypair::KeyPair;
+
+    #[test]
+    fn sign_verify_works() {
+        let keypair = KeyPair::generate();
+        let mut tx = Transaction::new(keypair.address(), [3; 32], 3, 1, 1, Hasher::default());
+        assert!(!tx.verify());
+
+        tx.sign(&keypair).unwrap();
+        assert!(tx.verify());
+
+        // 签名之后篡改金额
+        tx.amount = 30;
+        assert!(!tx.verify());
+
+        // 别人的私钥不能签名
+        let other = KeyPair::generate();
+        assert!(tx.sign(&other).is_err());
+    }
+
+    #[test]
+    fn verify_hash_works() {
+        let h = Hasher::default();
+        let keypair = KeyPair::generate();
+        let mut tx = Transaction::new(keypair.address(), [3; 32], 3, 1, 1, h);
+        let hash = tx.hash;
+        tx.sign(&keypair).unwrap();
+        // 签名不影响 hash
+        assert_eq!(tx.hash, hash);
+        assert!(tx.verify_hash(h));
+
+        tx.fee = 2;
+        assert!(!tx.verify_hash(h));
+        tx.set_hash(h);
+        assert!(tx.verify_hash(h));
+        // 不同的 hash 函数
+        assert!(!tx.verify_hash(Hasher::Blake2b));
+
+        // coinbase 的数据参与 hash
+        let a = Transaction::new_coinbase([8; 32], 50, b"coinbase 1", h);
+        let b = Transaction::new_coinbase([8; 32], 50, b"coinbase 2", h);
+        assert_ne!(a.hash, b.hash);
+    }
+}
@@ -0,0 +1,448 @@
+/// Merkle Patricia Trie
+/// https://eth.wiki/fundamentals/patricia-tree
+///
+/// key 按 4 bit（nibble）拆分成路径，三种节点：
+/// - Leaf : 剩余路径 + value
+/// - Extension : 公共路径 + 子节点 hash
+/// - Branch : 16 个子节点 hash + value
+///
+/// 每个节点以 hash 为 key 存储，节点不可变，修改只会产生新节点，所以任意历史 root 都可以查询。
+/// 从 root 到叶子路径上的节点就是 key 的证明（proof）。
+///
+use crate::core::storage::Storage;
+use crate::error::{Error, Result};
+use crate::utils::coder::{self, Hasher};
+use crate::utils::key::{Column, DbKey};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// 空树的 root
+pub const EMPTY_ROOT: [u8; 32] = [0; 32];
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+enum Node {
+    Leaf(Vec<u8>, Vec<u8>),
+    Extension(Vec<u8>, [u8; 32]),
+    Branch(Box<[Option<[u8; 32]>; 16]>, Option<Vec<u8>>),
+}
+
+/// 节点存储，只读
+pub trait TrieDb {
+    fn get_node(&self, hash: &[u8; 32]) -> Result<Option<Vec<u8>>>;
+}
+
+impl TrieDb for HashMap<[u8; 32], Vec<u8>> {
+    fn get_node(&self, hash: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(hash).cloned())
+    }
+}
+
+impl TrieDb for Arc<dyn Storage> {
+    fn get_node(&self, hash: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+        self.get(DbKey::new(Column::State, hash).as_bytes())
+    }
+}
+
+fn to_nibbles(key: &[u8]) -> Vec<u8> {
+    let mut nibbles = Vec::with_capacity(key.len() * 2);
+    for b in key {
+        nibbles.push(b >> 4);
+        nibbles.push(b & 0x0F);
+    }
+    nibbles
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count()
+}
+
+fn concat(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut v = a.to_vec();
+    v.extend_from_slice(b);
+    v
+}
+
+pub struct Trie<'a> {
+    db: &'a dyn TrieDb,
+    /// 新产生的节点，由调用者写入 db
+    pending: HashMap<[u8; 32], Vec<u8>>,
+    root: [u8; 32],
+    /// 计算节点 hash ，见 ChainConfig.hasher
+    hasher: Hasher,
+}
+
+impl<'a> Trie<'a> {
+    pub fn new(db: &'a dyn TrieDb, root: [u8; 32], hasher: Hasher) -> Trie<'a> {
+        Trie {
+            db,
+            pending: HashMap::new(),
+            root,
+            hasher,
+        }
+    }
+
+    pub fn root(&self) -> [u8; 32] {
+        self.root
+    }
+
+    /// 新产生的节点 hash -> 节点数据
+    pub fn into_pending(self) -> HashMap<[u8; 32], Vec<u8>> {
+        self.pending
+    }
+
+    fn load_raw(&self, hash: &[u8; 32]) -> Result<Vec<u8>> {
+        if let Some(data) = self.pending.get(hash) {
+            return Ok(data.clone());
+        }
+        self.db
+            .get_node(hash)?
+            .ok_or_else(|| Error::Storage(format!("trie node missing: {:?}", hash)))
+    }
+
+    fn load(&self, hash: &[u8; 32]) -> Result<Node> {
+        coder::deserialize(&self.load_raw(hash)?)
+    }
+
+    fn store(&mut self, node: Node) -> [u8; 32] {
+        let data = coder::serialize(&node);
+        let hash = self.hasher.hash(&data);
+        self.pending.insert(hash, data);
+        hash
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let path = to_nibbles(key);
+        let mut path = &path[..];
+        let mut hash = self.root;
+        if hash == EMPTY_ROOT {
+            return Ok(None);
+        }
+
+        loop {
+            match self.load(&hash)? {
+                Node::Leaf(p, v) => return Ok(if p == path { Some(v) } else { None }),
+                Node::Extension(p, child) => {
+                    if !path.starts_with(&p) {
+                        return Ok(None);
+                    }
+                    path = &path[p.len()..];
+                    hash = child;
+                }
+                Node::Branch(children, v) => {
+                    if path.is_empty() {
+                        return Ok(v);
+                    }
+                    hash = match children[path[0] as usize] {
+                        Some(child) => child,
+                        None => return Ok(None),
+                    };
+                    path = &path[1..];
+                }
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let root = if self.root == EMPTY_ROOT {
+            None
+        } else {
+            Some(self.root)
+        };
+        self.root = self.insert_at(root, &to_nibbles(key), value)?;
+        Ok(())
+    }
+
+    fn insert_at(
+        &mut self,
+        hash: Option<[u8; 32]>,
+        path: &[u8],
+        value: Vec<u8>,
+    ) -> Result<[u8; 32]> {
+        let hash = match hash {
+            Some(hash) => hash,
+            None => return Ok(self.store(Node::Leaf(path.to_vec(), value))),
+        };
+
+        let hash = match self.load(&hash)? {
+            Node::Leaf(p, v) => {
+                if p == path {
+                    return Ok(self.store(Node::Leaf(p, value)));
+                }
+                let c = common_prefix(&p, path);
+                let mut children = Box::new([None; 16]);
+                let mut branch_value = None;
+                for (rest, v) in [(&p[c..], v), (&path[c..], value)] {
+                    if rest.is_empty() {
+                        branch_value = Some(v);
+                    } else {
+                        children[rest[0] as usize] =
+                            Some(self.store(Node::Leaf(rest[1..].to_vec(), v)));
+                    }
+                }
+                let branch = self.store(Node::Branch(children, branch_value));
+                self.wrap_extension(&path[..c], branch)
+            }
+            Node::Extension(p, child) => {
+                let c = common_prefix(&p, path);
+                if c == p.len() {
+                    let child = self.insert_at(Some(child), &path[c..], value)?;
+                    return Ok(self.store(Node::Extension(p, child)));
+                }
+
+                // p[c] 一定存在
+                let mut children = Box::new([None; 16]);
+                let mut branch_value = None;
+                children[p[c] as usize] = Some(self.wrap_extension(&p[c + 1..], child));
+                let rest = &path[c..];
+                if rest.is_empty() {
+                    branch_value = Some(value);
+                } else {
+                    children[rest[0] as usize] =
+                        Some(self.store(Node::Leaf(rest[1..].to_vec(), value)));
+                }
+                let branch = self.store(Node::Branch(children, branch_value));
+                self.wrap_extension(&path[..c], branch)
+            }
+            Node::Branch(mut children, v) => {
+                if path.is_empty() {
+                    return Ok(self.store(Node::Branch(children, Some(value))));
+                }
+                let i = path[0] as usize;
+                children[i] = Some(self.insert_at(children[i], &path[1..], value)?);
+                self.store(Node::Branch(children, v))
+            }
+        };
+        Ok(hash)
+    }
+
+    /// path 为空时不需要 Extension
+    fn wrap_extension(&mut self, path: &[u8], child: [u8; 32]) -> [u8; 32] {
+        if path.is_empty() {
+            child
+        } else {
+            self.store(Node::Extension(path.to_vec(), child))
+        }
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        if self.root == EMPTY_ROOT {
+            return Ok(());
+        }
+        self.root = self
+            .delete_at(self.root, &to_nibbles(key))?
+            .unwrap_or(EMPTY_ROOT);
+        Ok(())
+    }
+
+    /// 返回 None 表示删除后子树为空
+    fn delete_at(&mut self, hash: [u8; 32], path: &[u8]) -> Result<Option<[u8; 32]>> {
+        match self.load(&hash)? {
+            Node::Leaf(p, _) => {
+                if p == path {
+                    Ok(None)
+                } else {
+                    Ok(Some(hash))
+                }
+            }
+            Node::Extension(p, child) => {
+                if !path.starts_with(&p) {
+                    return Ok(Some(hash));
+                }
+                let new_child = match self.delete_at(child, &path[p.len()..])? {
+                    Some(new_child) => new_child,
+                    None => return Ok(None),
+                };
+                if new_child == child {
+                    return Ok(Some(hash));
+                }
+                Ok(Some(self.merge_prefix(&p, new_child)?))
+            }
+            Node::Branch(mut children, mut v) => {
+                // key 不存在时不改变
+                if path.is_empty() {
+                    if v.is_none() {
+                        return Ok(Some(hash));
+                    }
+                    v = None;
+                } else {
+                    let i = path[0] as usize;
+                    let child = match children[i] {
+                        Some(child) => child,
+                        None => return Ok(Some(hash)),
+                    };
+                    children[i] = self.delete_at(child, &path[1..])?;
+                    if children[i] == Some(child) {
+                        return Ok(Some(hash));
+                    }
+                }
+
+                let mut iter = children.iter().enumerate().filter(|(_, c)| c.is_some());
+                let hash = match (iter.next(), iter.next(), &v) {
+                    (None, _, None) => None,
+                    (None, _, Some(v)) => Some(self.store(Node::Leaf(Vec::new(), v.clone()))),
+                    (Some((i, Some(child))), None, None) => {
+                        let child = *child;
+                        Some(self.merge_prefix(&[i as u8], child)?)
+                    }
+                    _ => Some(self.store(Node::Branch(children, v))),
+                };
+                Ok(hash)
+            }
+        }
+    }
+
+    /// 在 hash 节点前面加上 prefix 路径，Leaf 和 Extension 直接合并路径
+    fn merge_prefix(&mut self, prefix: &[u8], hash: [u8; 32]) -> Result<[u8; 32]> {
+        let hash = match self.load(&hash)? {
+            Node::Leaf(p, v) => self.store(Node::Leaf(concat(prefix, &p), v)),
+            Node::Extension(p, child) => self.store(Node::Extension(concat(prefix, &p), child)),
+            Node::Branch(_, _) => self.wrap_extension(prefix, hash),
+        };
+        Ok(hash)
+    }
+
+    /// 从 root 开始经过的所有节点
+    pub fn prove(&self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let path = to_nibbles(key);
+        let mut path = &path[..];
+        let mut proof = Vec::new();
+        let mut hash = self.root;
+        if hash == EMPTY_ROOT {
+            return Ok(proof);
+        }
+
+        loop {
+            let data = self.load_raw(&hash)?;
+            let node: Node = coder::deserialize(&data)?;
+            proof.push(data);
+            match node {
+                Node::Leaf(_, _) => return Ok(proof),
+                Node::Extension(p, child) => {
+                    if !path.starts_with(&p) {
+                        return Ok(proof);
+                    }
+                    path = &path[p.len()..];
+                    hash = child;
+                }
+                Node::Branch(children, _) => {
+                    if path.is_empty() {
+                        return Ok(proof);
+                    }
+                    match children[path[0] as usize] {
+                        Some(child) => hash = child,
+                        None => return Ok(proof),
+                    }
+                    path = &path[1..];
+                }
+            }
+        }
+    }
+}
+
+/// 节点引用的子节点 hash ，用于遍历整棵树
+pub fn node_children(data: &[u8]) -> Result<Vec<[u8; 32]>> {
+    Ok(match coder::deserialize(data)? {
+        Node::Leaf(..) => Vec::new(),
+        Node::Extension(_, child) => vec![child],
+        Node::Branch(children, _) => children.iter().flatten().copied().collect(),
+    })
+}
+
+/// 验证 proof ，返回 key 在 root 下的 value ，Ok(None) 表示 key 不存在
+pub fn verify_proof(
+    root: &[u8; 32],
+    key: &[u8],
+    proof: &[Vec<u8>],
+    hasher: Hasher,
+) -> Result<Option<Vec<u8>>> {
+    let mut nodes = HashMap::new();
+    for data in proof {
+        nodes.insert(hasher.hash(data), data.clone());
+    }
+    if *root != EMPTY_ROOT && !nodes.contains_key(root) {
+        return Err(Error::Proof("proof does not contain root".to_string()));
+    }
+
+    // 路径上缺少节点或节点无法解码都说明 proof 不正确
+    Trie::new(&nodes, *root, hasher)
+        .get(key)
+        .map_err(|e| Error::Proof(format!("proof is incomplete: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_proof, Trie, EMPTY_ROOT};
+    use crate::utils::coder::Hasher;
+    use std::collections::HashMap;
+
+    const H: Hasher = Hasher::Sha3_256;
+
+    fn key(i: u8) -> [u8; 32] {
+        let mut k = [i; 32];
+        // 制造公共前缀
+        k[0] = i % 3;
+        k
+    }
+
+    #[test]
+    fn trie_works() {
+        let db = HashMap::new();
+        let mut trie = Trie::new(&db, EMPTY_ROOT, H);
+        for i in 0..20u8 {
+            trie.insert(&key(i), vec![i]).unwrap();
+        }
+        for i in 0..20u8 {
+            assert_eq!(trie.get(&key(i)).unwrap(), Some(vec![i]));
+        }
+        assert_eq!(trie.get(&[99; 32]).unwrap(), None);
+
+        // root 与插入顺序无关
+        let mut other = Trie::new(&db, EMPTY_ROOT, H);
+        for i in (0..20u8).rev() {
+            other.insert(&key(i), vec![i]).unwrap();
+        }
+        assert_eq!(trie.root(), other.root());
+
+        // 删除后与没有插入过一样
+        let mut half = Trie::new(&db, EMPTY_ROOT, H);
+        for i in 0..10u8 {
+            half.insert(&key(i), vec![i]).unwrap();
+        }
+        for i in 10..20u8 {
+            trie.delete(&key(i)).unwrap();
+        }
+        assert_eq!(trie.root(), half.root());
+        assert_eq!(trie.get(&key(15)).unwrap(), None);
+
+        for i in 0..10u8 {
+            trie.delete(&key(i)).unwrap();
+        }
+        assert_eq!(trie.root(), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn proof_works() {
+        let db = HashMap::new();
+        let mut trie = Trie::new(&db, EMPTY_ROOT, H);
+        for i in 0..20u8 {
+            trie.insert(&key(i), vec![i]).unwrap();
+        }
+        let root = trie.root();
+
+        let proof = trie.prove(&key(7)).unwrap();
+        assert_eq!(verify_proof(&root, &key(7), &proof, H), Ok(Some(vec![7])));
+        // 同一个 proof 不能证明别的 key
+        assert!(verify_proof(&root, &key(8), &proof, H) != Ok(Some(vec![8])));
+
+        // 不存在的证明
+        let proof = trie.prove(&[99; 32]).unwrap();
+        assert_eq!(verify_proof(&root, &[99; 32], &proof, H), Ok(None));
+
+        // 篡改 value
+        let mut other = Trie::new(&db, EMPTY_ROOT, H);
+        other.insert(&key(7), vec![70]).unwrap();
+        let proof = other.prove(&key(7)).unwrap();
+        assert!(verify_proof(&root, &key(7), &proof, H).is_err());
+    }
+}
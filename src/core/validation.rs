@@ -0,0 +1,350 @@
+/// 区块验证
+/// https://en.bitcoin.it/wiki/Protocol_rules#.22block.22_messages
+///
+/// - check_block : 只依赖区块本身（hash、pow、merkle root、大小、coinbase、签名）
+/// - check_context : 依赖父区块（高度、时间戳）
+/// - check_coinbase : 依赖高度（出块奖励）
+///
+use crate::core::block::{Block, BlockHeader};
+use crate::core::pow::ProofOfWork;
+use crate::core::wire;
+use crate::utils::coder::Hasher;
+use std::error::Error;
+use std::fmt;
+
+/// 时间戳不能超过本机时间 2 小时
+pub const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60;
+/// 时间戳不能早于前 11 个区块时间戳的中位数
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BlockError {
+    /// 区块已经存在
+    AlreadyKnown,
+    /// header 重新计算的 hash 不一致
+    BadHash,
+    /// hash 不满足 bits 的 target
+    HighHash,
+    /// tx_hash 与交易的 merkle root 不一致
+    BadMerkleRoot,
+    /// pre_hash 不是已知区块
+    UnknownParent,
+    BadHeight {
+        expected: u64,
+        found: u64,
+    },
+    /// bits 与难度调整的结果不一致
+    BadBits {
+        expected: u32,
+        found: u32,
+    },
+    TimeTooOld,
+    TimeTooNew,
+    /// 第一笔交易必须是 coinbase
+    NoCoinbase,
+    /// 只能有一笔 coinbase
+    MultipleCoinbase,
+    /// 交易 hash 与内容不一致，交易在区块中的位置
+    BadTxHash(usize),
+    /// 交易签名错误，交易在区块中的位置
+    BadSignature(usize),
+    /// 交易 nonce 不是账户 nonce + 1
+    BadNonce(usize),
+    /// 余额不足以支付 amount + fee
+    InsufficientBalance(usize),
+    /// 执行交易之后的 state_root 与区块头不一致
+    BadStateRoot,
+    /// 区块超过 ChainConfig.max_block_size
+    TooLarge,
+    /// coinbase 金额超过出块奖励 + 手续费
+    BadCoinbase {
+        max: u64,
+        found: u64,
+    },
+    /// 花费了还没有成熟的 coinbase ，交易在区块中的位置
+    ImmatureCoinbase(usize),
+    /// 收款账户的余额超过 u64 ，交易在区块中的位置
+    BalanceOverflow(usize),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::AlreadyKnown => write!(f, "block already known"),
+            BlockError::BadHash => write!(f, "block hash does not match header"),
+            BlockError::HighHash => write!(f, "block hash does not meet target"),
+            BlockError::BadMerkleRoot => write!(f, "tx_hash does not match merkle root"),
+            BlockError::UnknownParent => write!(f, "pre_hash is not a known block"),
+            BlockError::BadHeight { expected, found } => {
+                write!(f, "bad height, expected {} found {}", expected, found)
+            }
+            BlockError::BadBits { expected, found } => {
+                write!(f, "bad bits, expected {:#x} found {:#x}", expected, found)
+            }
+            BlockError::TimeTooOld => write!(f, "block time is before median time past"),
+            BlockError::TimeTooNew => write!(f, "block time is too far in the future"),
+            BlockError::NoCoinbase => write!(f, "first transaction is not coinbase"),
+            BlockError::MultipleCoinbase => write!(f, "more than one coinbase"),
+            BlockError::BadTxHash(i) => write!(f, "bad hash in transaction {}", i),
+            BlockError::BadSignature(i) => write!(f, "bad signature in transaction {}", i),
+            BlockError::BadNonce(i) => write!(f, "bad nonce in transaction {}", i),
+            BlockError::InsufficientBalance(i) => {
+                write!(f, "insufficient balance in transaction {}", i)
+            }
+            BlockError::BadStateRoot => write!(f, "state_root does not match header"),
+            BlockError::TooLarge => write!(f, "block exceeds max block size"),
+            BlockError::BadCoinbase { max, found } => {
+                write!(f, "coinbase claims {} but at most {} allowed", found, max)
+            }
+            BlockError::ImmatureCoinbase(i) => {
+                write!(f, "transaction {} spends immature coinbase", i)
+            }
+            BlockError::BalanceOverflow(i) => write!(f, "balance overflow in transaction {}", i),
+        }
+    }
+}
+
+impl Error for BlockError {}
+
+/// 与链无关的检查
+///
+/// # Arguments
+/// * max_size - wire 编码后的区块最大字节数，ChainConfig.max_block_size
+/// * hasher - 区块头、交易和 merkle 树的 hash 函数，ChainConfig.hasher
+pub fn check_block(b: &Block, max_size: usize, hasher: Hasher) -> Result<(), BlockError> {
+    if b.header.hash(hasher) != b.hash {
+        return Err(BlockError::BadHash);
+    }
+    if !ProofOfWork::new(b.header.bits).check(&b.hash) {
+        return Err(BlockError::HighHash);
+    }
+    // 交易按 tx.hash 索引，先确认 hash 与交易内容一致，merkle root 由包括签名的 witness_hash 计算
+    for (i, tx) in b.transactions.iter().enumerate() {
+        if !tx.verify_hash(hasher) {
+            return Err(BlockError::BadTxHash(i));
+        }
+    }
+    if Block::tx_merkle_root(&b.transactions, hasher) != b.header.tx_hash {
+        return Err(BlockError::BadMerkleRoot);
+    }
+    if wire::encode_block(b).len() > max_size {
+        return Err(BlockError::TooLarge);
+    }
+
+    match b.transactions.first() {
+        Some(tx) if tx.is_coinbase() => {}
+        _ => return Err(BlockError::NoCoinbase),
+    }
+    for (i, tx) in b.transactions.iter().enumerate().skip(1) {
+        if tx.is_coinbase() {
+            return Err(BlockError::MultipleCoinbase);
+        }
+        if !tx.verify() {
+            return Err(BlockError::BadSignature(i));
+        }
+    }
+
+    Ok(())
+}
+
+/// coinbase 最多领取出块奖励和所有交易的手续费
+///
+/// # Arguments
+/// * subsidy - 区块高度对应的出块奖励，ChainConfig.subsidy
+pub fn check_coinbase(b: &Block, subsidy: u64) -> Result<(), BlockError> {
+    let coinbase = match b.transactions.first() {
+        Some(tx) => tx,
+        None => return Err(BlockError::NoCoinbase),
+    };
+    // 手续费总和溢出时交易不可能都有足够的余额，执行时会失败
+    let max = b
+        .transactions
+        .iter()
+        .skip(1)
+        .fold(subsidy, |sum, tx| sum.saturating_add(tx.fee));
+    if coinbase.amount > max {
+        return Err(BlockError::BadCoinbase {
+            max,
+            found: coinbase.amount,
+        });
+    }
+
+    Ok(())
+}
+
+/// 与父区块相关的检查
+///
+/// # Arguments
+/// * parent - pre_hash 对应的区块头
+/// * expected_bits - 父区块之后难度调整的结果
+/// * median_time - 父区块及之前最多 MEDIAN_TIME_SPAN 个区块时间戳的中位数
+/// * now - 本机时间
+pub fn check_context(
+    header: &BlockHeader,
+    parent: &BlockHeader,
+    expected_bits: u32,
+    median_time: i64,
+    now: i64,
+) -> Result<(), BlockError> {
+    if header.height != parent.height + 1 {
+        return Err(BlockError::BadHeight {
+            expected: parent.height + 1,
+            found: header.height,
+        });
+    }
+    if header.bits != expected_bits {
+        return Err(BlockError::BadBits {
+            expected: expected_bits,
+            found: header.bits,
+        });
+    }
+    // 出块很快时时间戳会相同，所以允许等于
+    if header.time < median_time {
+        return Err(BlockError::TimeTooOld);
+    }
+    if header.time > now + MAX_FUTURE_BLOCK_TIME {
+        return Err(BlockError::TimeTooNew);
+    }
+
+    Ok(())
+}
+
+pub fn median_time(mut times: Vec<i64>) -> i64 {
+    if times.is_empty() {
+        return 0;
+    }
+    times.sort_unstable();
+    times[times.len() / 2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_block, check_coinbase, check_context, BlockError};
+    use crate::core::block::{Block, MAX_BLOCK_SIZE};
+    use crate::core::pow::ProofOfWork;
+    use crate::core::transaction::Transaction;
+    use crate::utils::coder::Hasher;
+    use crate::utils::keypair::KeyPair;
+    use std::sync::atomic::AtomicBool;
+
+    /// 0x2100FFFF 的 target 很大，很快就能算出来
+    const BITS: u32 = 0x2100FFFF;
+    const H: Hasher = Hasher::Sha3_256;
+
+    fn mined_block(txs: Vec<Transaction>) -> Block {
+        let mut b = Block::new(txs, [0; 32], BITS, 1, H);
+        ProofOfWork::new(BITS).run(&mut b, H, &AtomicBool::new(false));
+        b
+    }
+
+    fn signed_tx() -> Transaction {
+        let keypair = KeyPair::generate();
+        let mut tx = Transaction::new(keypair.address(), [3; 32], 3, 1, 1, H);
+        tx.sign(&keypair).unwrap();
+        tx
+    }
+
+    #[test]
+    fn check_block_works() {
+        let coinbase = Transaction::new_coinbase([8; 32], 0, b"coinbase", H);
+        let b = mined_block(vec![coinbase.clone(), signed_tx()]);
+        assert_eq!(check_block(&b, MAX_BLOCK_SIZE, H), Ok(()));
+
+        let mut bad = b.clone();
+        bad.header.nonce += 1;
+        assert_eq!(
+            check_block(&bad, MAX_BLOCK_SIZE, H),
+            Err(BlockError::BadHash)
+        );
+
+        let mut bad = b.clone();
+        bad.transactions.pop();
+        assert_eq!(
+            check_block(&bad, MAX_BLOCK_SIZE, H),
+            Err(BlockError::BadMerkleRoot)
+        );
+
+        let b = mined_block(vec![signed_tx()]);
+        assert_eq!(
+            check_block(&b, MAX_BLOCK_SIZE, H),
+            Err(BlockError::NoCoinbase)
+        );
+
+        let b = mined_block(vec![coinbase.clone(), coinbase]);
+        assert_eq!(
+            check_block(&b, MAX_BLOCK_SIZE, H),
+            Err(BlockError::MultipleCoinbase)
+        );
+
+        let mut tx = signed_tx();
+        tx.amount += 1;
+        let b = mined_block(vec![
+            Transaction::new_coinbase([8; 32], 0, b"", H),
+            tx.clone(),
+        ]);
+        assert_eq!(
+            check_block(&b, MAX_BLOCK_SIZE, H),
+            Err(BlockError::BadTxHash(1))
+        );
+
+        tx.set_hash(H);
+        let b = mined_block(vec![Transaction::new_coinbase([8; 32], 0, b"", H), tx]);
+        assert_eq!(
+            check_block(&b, MAX_BLOCK_SIZE, H),
+            Err(BlockError::BadSignature(1))
+        );
+    }
+
+    #[test]
+    fn check_coinbase_works() {
+        // signed_tx 的手续费是 1
+        let coinbase = Transaction::new_coinbase([8; 32], 51, b"coinbase", H);
+        let b = mined_block(vec![coinbase, signed_tx()]);
+        assert_eq!(check_coinbase(&b, 50), Ok(()));
+        assert_eq!(
+            check_coinbase(&b, 49),
+            Err(BlockError::BadCoinbase { max: 50, found: 51 })
+        );
+    }
+
+    #[test]
+    fn check_context_works() {
+        let coinbase = Transaction::new_coinbase([8; 32], 0, b"coinbase", H);
+        let parent = Block::new(vec![coinbase.clone()], [0; 32], BITS, 0, H).header;
+        let mut header = Block::new(vec![coinbase], [0; 32], BITS, 1, H).header;
+        let now = header.time;
+
+        assert_eq!(
+            check_context(&header, &parent, BITS, parent.time, now),
+            Ok(())
+        );
+        assert_eq!(
+            check_context(&header, &parent, BITS, now + 1, now),
+            Err(BlockError::TimeTooOld)
+        );
+
+        header.time = now + 3 * 60 * 60;
+        assert_eq!(
+            check_context(&header, &parent, BITS, parent.time, now),
+            Err(BlockError::TimeTooNew)
+        );
+
+        header.time = now;
+        assert_eq!(
+            check_context(&header, &parent, 0x1d00ffff, parent.time, now),
+            Err(BlockError::BadBits {
+                expected: 0x1d00ffff,
+                found: BITS
+            })
+        );
+
+        header.height = 2;
+        assert_eq!(
+            check_context(&header, &parent, BITS, parent.time, now),
+            Err(BlockError::BadHeight {
+                expected: 1,
+                found: 2
+            })
+        );
+    }
+}
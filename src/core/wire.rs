@@ -0,0 +1,410 @@
+/// 区块和交易的二进制编码，计算 hash 和网络传输都使用它
+///
+/// 与 Rust 结构体的字段顺序、bincode 的实现无关，其他语言的客户端按下面的格式编码可以得到相同的 hash 。
+///
+/// - 整数：固定长度，小端（与 bitcoin 相同）
+/// - [u8; 32] ：原样 32 字节
+/// - 列表和字节串：varint 长度 + 内容，varint 为无符号 LEB128 ，必须是最短编码
+///
+/// ```text
+/// Transaction  = version:u8 from:[32] to:[32] amount:u64 fee:u64 nonce:u64 sign:varint+bytes
+/// BlockHeader  = version:u8 height:u64 time:i64 tx_hash:[32] pre_hash:[32] bits:u32 nonce:u32 state_root:[32]
+/// Block        = BlockHeader count:varint Transaction*
+/// merkle 叶子  = hash(Transaction) ，即 Transaction::witness_hash ，包括签名
+/// merkle 节点  = hash(left:[32] right:[32])
+///
+/// TxPayload      = version:u8 from:[32] to:[32] amount:u64 fee:u64 nonce:u64 data:varint+bytes
+/// AccountPayload = version:u8 nonce:u64 balance:u64 address:[32]
+/// ```
+///
+/// Transaction.hash 和 Block.hash 由内容计算，不编码。
+/// Transaction.hash 和签名都对 TxPayload 计算，from 为 0 的交易（coinbase 和 genesis 的交易）
+/// data 是 sign 字段中的数据，其他交易为空；
+/// 区块头的 tx_hash 是 merkle 叶子的 root ，所以区块头也确定了交易的签名；
+/// Account.hash 对 AccountPayload 计算。
+///
+use crate::core::account::Account;
+use crate::core::block::{Block, BlockHeader};
+use crate::core::transaction::Transaction;
+use crate::error::{Error, Result};
+use crate::utils::coder::Hasher;
+
+/// 当前的编码版本，解码时不认识的版本返回错误
+pub const WIRE_VERSION: u8 = 1;
+
+/// 网络消息（见 net::message）也用它编码
+pub(crate) struct Writer {
+    pub(crate) buf: Vec<u8>,
+}
+
+impl Writer {
+    pub(crate) fn new() -> Writer {
+        Writer { buf: Vec::new() }
+    }
+
+    pub(crate) fn put_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub(crate) fn put_u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub(crate) fn put_u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub(crate) fn put_i64(&mut self, v: i64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub(crate) fn put_hash(&mut self, v: &[u8; 32]) {
+        self.buf.extend_from_slice(v);
+    }
+
+    pub(crate) fn put_varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.buf.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.buf.push(v as u8);
+    }
+
+    pub(crate) fn put_bytes(&mut self, v: &[u8]) {
+        self.put_varint(v.len() as u64);
+        self.buf.extend_from_slice(v);
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    pub(crate) buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(Error::Codec("unexpected end of data".to_string()));
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut a = [0u8; N];
+        a.copy_from_slice(self.take(N)?);
+        Ok(a)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn varint(&mut self) -> Result<u64> {
+        let mut v = 0u64;
+        for i in 0..10 {
+            let byte = self.u8()?;
+            let bits = (byte & 0x7F) as u64;
+            // 第 10 个字节只能有 1 位
+            if i == 9 && bits > 1 {
+                return Err(Error::Codec("varint overflow".to_string()));
+            }
+            v |= bits << (7 * i);
+            if byte & 0x80 == 0 {
+                if byte == 0 && i > 0 {
+                    return Err(Error::Codec("non-minimal varint".to_string()));
+                }
+                return Ok(v);
+            }
+        }
+        Err(Error::Codec("varint overflow".to_string()))
+    }
+
+    /// 长度超过剩余数据时直接失败，不会按长度预先分配内存
+    pub(crate) fn len(&mut self) -> Result<usize> {
+        let n = self.varint()?;
+        if n > self.buf.len() as u64 {
+            return Err(Error::Codec("length exceeds data".to_string()));
+        }
+        Ok(n as usize)
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<Vec<u8>> {
+        let n = self.len()?;
+        Ok(self.take(n)?.to_vec())
+    }
+
+    pub(crate) fn version(&mut self) -> Result<()> {
+        match self.u8()? {
+            WIRE_VERSION => Ok(()),
+            v => Err(Error::Codec(format!("unknown wire version {}", v))),
+        }
+    }
+
+    pub(crate) fn finish(&self) -> Result<()> {
+        if !self.buf.is_empty() {
+            return Err(Error::Codec("trailing bytes".to_string()));
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn write_tx(w: &mut Writer, tx: &Transaction) {
+    w.put_u8(WIRE_VERSION);
+    w.put_hash(&tx.from);
+    w.put_hash(&tx.to);
+    w.put_u64(tx.amount);
+    w.put_u64(tx.fee);
+    w.put_u64(tx.nonce);
+    w.put_bytes(&tx.sign);
+}
+
+pub(crate) fn read_tx(r: &mut Reader, hasher: Hasher) -> Result<Transaction> {
+    r.version()?;
+    let mut tx = Transaction {
+        hash: [0; 32],
+        from: r.array()?,
+        to: r.array()?,
+        amount: r.u64()?,
+        fee: r.u64()?,
+        nonce: r.u64()?,
+        sign: r.bytes()?,
+    };
+    tx.set_hash(hasher);
+    Ok(tx)
+}
+
+pub(crate) fn write_header(w: &mut Writer, header: &BlockHeader) {
+    w.put_u8(WIRE_VERSION);
+    w.put_u64(header.height);
+    w.put_i64(header.time);
+    w.put_hash(&header.tx_hash);
+    w.put_hash(&header.pre_hash);
+    w.put_u32(header.bits);
+    w.put_u32(header.nonce);
+    w.put_hash(&header.state_root);
+}
+
+pub(crate) fn read_header(r: &mut Reader) -> Result<BlockHeader> {
+    r.version()?;
+    Ok(BlockHeader {
+        height: r.u64()?,
+        time: r.i64()?,
+        tx_hash: r.array()?,
+        pre_hash: r.array()?,
+        bits: r.u32()?,
+        nonce: r.u32()?,
+        state_root: r.array()?,
+    })
+}
+
+/// 交易中不包括 hash 和签名的部分
+pub fn encode_tx_payload(tx: &Transaction) -> Vec<u8> {
+    let mut w = Writer::new();
+    w.put_u8(WIRE_VERSION);
+    w.put_hash(&tx.from);
+    w.put_hash(&tx.to);
+    w.put_u64(tx.amount);
+    w.put_u64(tx.fee);
+    w.put_u64(tx.nonce);
+    if tx.from == [0; 32] {
+        w.put_bytes(&tx.sign);
+    } else {
+        w.put_bytes(&[]);
+    }
+    w.buf
+}
+
+/// 账户状态中不包括 hash 的部分
+pub fn encode_account_payload(account: &Account) -> Vec<u8> {
+    let mut w = Writer::new();
+    w.put_u8(WIRE_VERSION);
+    w.put_u64(account.nonce);
+    w.put_u64(account.balance);
+    w.put_hash(&account.address);
+    w.buf
+}
+
+pub fn encode_tx(tx: &Transaction) -> Vec<u8> {
+    let mut w = Writer::new();
+    write_tx(&mut w, tx);
+    w.buf
+}
+
+/// 解码后用链的 hash 函数重新计算 hash
+pub fn decode_tx(bytes: &[u8], hasher: Hasher) -> Result<Transaction> {
+    let mut r = Reader { buf: bytes };
+    let tx = read_tx(&mut r, hasher)?;
+    r.finish()?;
+    Ok(tx)
+}
+
+pub fn encode_header(header: &BlockHeader) -> Vec<u8> {
+    let mut w = Writer::new();
+    write_header(&mut w, header);
+    w.buf
+}
+
+pub fn decode_header(bytes: &[u8]) -> Result<BlockHeader> {
+    let mut r = Reader { buf: bytes };
+    let header = read_header(&mut r)?;
+    r.finish()?;
+    Ok(header)
+}
+
+pub fn encode_block(b: &Block) -> Vec<u8> {
+    let mut w = Writer::new();
+    write_header(&mut w, &b.header);
+    w.put_varint(b.transactions.len() as u64);
+    for tx in &b.transactions {
+        write_tx(&mut w, tx);
+    }
+    w.buf
+}
+
+/// 解码后用链的 hash 函数重新计算区块和交易的 hash
+pub fn decode_block(bytes: &[u8], hasher: Hasher) -> Result<Block> {
+    let mut r = Reader { buf: bytes };
+    let header = read_header(&mut r)?;
+    let n = r.len()?;
+    let mut transactions = Vec::with_capacity(n);
+    for _ in 0..n {
+        transactions.push(read_tx(&mut r, hasher)?);
+    }
+    r.finish()?;
+
+    Ok(Block {
+        hash: header.hash(hasher),
+        header,
+        transactions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_block, decode_header, decode_tx, encode_block, encode_header, encode_tx,
+        encode_tx_payload,
+    };
+    use crate::core::block::{Block, BlockHeader};
+    use crate::core::transaction::Transaction;
+    use crate::utils::coder::Hasher;
+    use crate::utils::hex;
+
+    const H: Hasher = Hasher::Sha3_256;
+
+    fn golden_tx() -> Transaction {
+        let mut tx = Transaction::new([1; 32], [2; 32], 300, 2, 1, H);
+        tx.sign = vec![0xAB; 130];
+        tx.set_hash(H);
+        tx
+    }
+
+    fn golden_header() -> BlockHeader {
+        BlockHeader {
+            height: 1,
+            time: 1609459200,
+            tx_hash: [3; 32],
+            pre_hash: [4; 32],
+            bits: 0x2100FFFF,
+            nonce: 7,
+            state_root: [5; 32],
+        }
+    }
+
+    #[test]
+    fn golden_vectors_work() {
+        let tx = golden_tx();
+        let expected = format!(
+            "01 {} {} 2c01000000000000 0200000000000000 0100000000000000 8201 {}",
+            "01".repeat(32),
+            "02".repeat(32),
+            "ab".repeat(130)
+        )
+        .replace(' ', "");
+        assert_eq!(hex::encode(&encode_tx(&tx)), expected);
+
+        let header = golden_header();
+        let expected = format!(
+            "01 0100000000000000 0066ee5f00000000 {} {} ffff0021 07000000 {}",
+            "03".repeat(32),
+            "04".repeat(32),
+            "05".repeat(32)
+        )
+        .replace(' ', "");
+        assert_eq!(hex::encode(&encode_header(&header)), expected);
+
+        // hash 对不包括签名的 payload 计算，默认的 sha3_256
+        let expected = format!(
+            "01 {} {} 2c01000000000000 0200000000000000 0100000000000000 00",
+            "01".repeat(32),
+            "02".repeat(32)
+        )
+        .replace(' ', "");
+        assert_eq!(hex::encode(&encode_tx_payload(&tx)), expected);
+        assert_eq!(
+            hex::encode(&tx.hash),
+            "8bb341be56d226280bc8f94dc011dde79231439d249ffaea39bfe9ce96202ed4"
+        );
+        assert_eq!(
+            hex::encode(&header.hash(H)),
+            "87a3d06cb99ddd11050471fca444bcc99a5171cb968c250981bb05bfcf9b879e"
+        );
+    }
+
+    #[test]
+    fn round_trip_works() {
+        let tx = golden_tx();
+        let decoded = decode_tx(&encode_tx(&tx), H).unwrap();
+        assert_eq!(decoded.hash, tx.hash);
+        assert_eq!(decoded.sign, tx.sign);
+
+        let header = golden_header();
+        assert_eq!(decode_header(&encode_header(&header)).unwrap(), header);
+
+        let coinbase = Transaction::new_coinbase([8; 32], 50, b"coinbase", H);
+        let b = Block::new(vec![coinbase, tx], [0; 32], 0x2100FFFF, 1, H);
+        let bytes = encode_block(&b);
+        let decoded = decode_block(&bytes, H).unwrap();
+        assert_eq!(decoded.header, b.header);
+        assert_eq!(decoded.hash, b.header.hash(H));
+        assert_eq!(encode_block(&decoded), bytes);
+    }
+
+    #[test]
+    fn bad_encoding_returns_error() {
+        let bytes = encode_tx(&golden_tx());
+        // 截断、多余的字节、未知版本
+        assert!(decode_tx(&bytes[..bytes.len() - 1], H).is_err());
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert!(decode_tx(&extra, H).is_err());
+        let mut version = bytes.clone();
+        version[0] = 2;
+        assert!(decode_tx(&version, H).is_err());
+
+        // sign 的长度 130 编码为 0x82 0x01 ，非最短编码 0x82 0x81 0x00 不接受
+        let at = 1 + 32 + 32 + 8 * 3;
+        let mut long = bytes[..at].to_vec();
+        long.extend_from_slice(&[0x82, 0x81, 0x00]);
+        long.extend_from_slice(&bytes[at + 2..]);
+        assert!(decode_tx(&long, H).is_err());
+
+        // 声明的交易数量远超数据长度
+        let mut b = encode_header(&golden_header());
+        b.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF, 0x0F]);
+        assert!(decode_block(&b, H).is_err());
+    }
+}
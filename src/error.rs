@@ -0,0 +1,81 @@
+/// 整个 crate 的错误类型
+///
+/// 嵌入到其他服务中时，数据库损坏、验证失败都只返回错误，不会让进程退出。
+///
+use crate::core::mempool::MempoolError;
+use crate::core::validation::BlockError;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// 编码 / 解码失败，例如数据库中的数据损坏
+    Codec(String),
+    /// 数据库打开、读写失败，或者应该存在的数据不存在
+    Storage(String),
+    /// 区块没有通过验证
+    Validation(BlockError),
+    /// 链的数据不一致，例如 genesis 不一致、主链区块缺失
+    Consensus(String),
+    /// 账户和签名，例如私钥与地址不一致、余额不足
+    Account(String),
+    /// 交易池拒绝交易
+    Mempool(MempoolError),
+    /// 账户证明或 merkle 证明不正确
+    Proof(String),
+    /// 配置文件或命令行参数不正确
+    Config(String),
+    /// 连接失败、握手失败或者对方发送了错误的消息
+    Network(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Codec(e) => write!(f, "codec error: {}", e),
+            Error::Storage(e) => write!(f, "storage error: {}", e),
+            Error::Validation(e) => write!(f, "invalid block: {}", e),
+            Error::Consensus(e) => write!(f, "consensus error: {}", e),
+            Error::Account(e) => write!(f, "account error: {}", e),
+            Error::Mempool(e) => write!(f, "transaction rejected: {}", e),
+            Error::Proof(e) => write!(f, "invalid proof: {}", e),
+            Error::Config(e) => write!(f, "invalid config: {}", e),
+            Error::Network(e) => write!(f, "network error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Validation(e) => Some(e),
+            Error::Mempool(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<BlockError> for Error {
+    fn from(e: BlockError) -> Self {
+        Error::Validation(e)
+    }
+}
+
+impl From<MempoolError> for Error {
+    fn from(e: MempoolError) -> Self {
+        Error::Mempool(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Error::Codec(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Storage(e.to_string())
+    }
+}
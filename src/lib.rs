@@ -1,3 +1,6 @@
 pub mod cli;
 pub mod core;
-mod utils;
+pub mod error;
+pub mod net;
+pub mod rpc;
+pub mod utils;
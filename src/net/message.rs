@@ -0,0 +1,307 @@
+/// 节点之间的消息
+///
+/// 每条消息是一个帧：length:u32 + command:u8 + body ，length 是 command 和 body 的字节数，小端。
+/// body 使用 wire 编码（见 core/wire.rs）：
+///
+/// ```text
+/// Version  = protocol:u32 network_id:u32 genesis:[32] best_height:u64 nonce:u64
+/// Verack   =
+/// Inv      = count:varint (kind:u8 hash:[32])*
+/// GetData  = count:varint (kind:u8 hash:[32])*
+/// Block    = wire Block
+/// Tx       = wire Transaction
+/// GetHeaders = count:varint hash:[32]*
+/// Headers  = count:varint BlockHeader*
+/// ```
+///
+/// 连接后双方都先发送 Version ，收到对方的 Version 并检查通过后回复 Verack ，
+/// 之后用 Inv 通知对方新的区块和交易，对方用 GetData 请求不知道的部分。
+/// 同步时用 GetHeaders 请求区块头，见 sync.rs 。
+///
+use crate::core::block::{Block, BlockHeader};
+use crate::core::transaction::Transaction;
+use crate::core::wire::{self, Reader, Writer};
+use crate::error::{Error, Result};
+use crate::utils::coder::Hasher;
+use std::io::{Read, Write};
+
+/// 协议版本，不同版本的节点不能连接
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// 一条 Inv / GetData 中最多的条目
+pub const MAX_INV: usize = 1000;
+
+/// 一条 Headers 中最多的区块头
+pub const MAX_HEADERS: usize = 2000;
+
+/// 一条 GetHeaders 中最多的 hash
+pub const MAX_LOCATOR: usize = 101;
+
+/// 除了区块之外的消息不会超过这个大小，MAX_HEADERS 个区块头约 240KB
+const MAX_OTHER_SIZE: usize = 512 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InvKind {
+    Block = 1,
+    Tx = 2,
+}
+
+/// 用 hash 表示的区块或交易
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InvItem {
+    pub kind: InvKind,
+    pub hash: [u8; 32],
+}
+
+impl InvItem {
+    pub fn block(hash: [u8; 32]) -> InvItem {
+        InvItem {
+            kind: InvKind::Block,
+            hash,
+        }
+    }
+
+    pub fn tx(hash: [u8; 32]) -> InvItem {
+        InvItem {
+            kind: InvKind::Tx,
+            hash,
+        }
+    }
+}
+
+/// 握手时交换的信息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub protocol: u32,
+    pub network_id: u32,
+    pub genesis: [u8; 32],
+    pub best_height: u64,
+    /// 每个节点启动时随机生成，用来发现连接到了自己
+    pub nonce: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Version(Version),
+    Verack,
+    Inv(Vec<InvItem>),
+    GetData(Vec<InvItem>),
+    Block(Block),
+    Tx(Transaction),
+    /// 请求 locator 之后的区块头，见 BlockChain::locator
+    GetHeaders(Vec<[u8; 32]>),
+    Headers(Vec<BlockHeader>),
+}
+
+fn write_items(w: &mut Writer, items: &[InvItem]) {
+    w.put_varint(items.len() as u64);
+    for item in items {
+        w.put_u8(item.kind as u8);
+        w.put_hash(&item.hash);
+    }
+}
+
+/// 列表的长度，超过 max 时返回错误
+fn read_count(r: &mut Reader, max: usize) -> Result<usize> {
+    let n = r.len()?;
+    if n > max {
+        return Err(Error::Codec(format!("too many items: {}", n)));
+    }
+    Ok(n)
+}
+
+fn read_items(r: &mut Reader) -> Result<Vec<InvItem>> {
+    let n = read_count(r, MAX_INV)?;
+    let mut items = Vec::with_capacity(n);
+    for _ in 0..n {
+        let kind = match r.u8()? {
+            1 => InvKind::Block,
+            2 => InvKind::Tx,
+            k => return Err(Error::Codec(format!("unknown inventory kind {}", k))),
+        };
+        items.push(InvItem {
+            kind,
+            hash: r.array()?,
+        });
+    }
+    Ok(items)
+}
+
+impl Message {
+    /// command + body
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        match self {
+            Message::Version(v) => {
+                w.put_u8(0);
+                w.put_u32(v.protocol);
+                w.put_u32(v.network_id);
+                w.put_hash(&v.genesis);
+                w.put_u64(v.best_height);
+                w.put_u64(v.nonce);
+            }
+            Message::Verack => w.put_u8(1),
+            Message::Inv(items) => {
+                w.put_u8(2);
+                write_items(&mut w, items);
+            }
+            Message::GetData(items) => {
+                w.put_u8(3);
+                write_items(&mut w, items);
+            }
+            Message::Block(b) => {
+                w.put_u8(4);
+                wire::write_header(&mut w, &b.header);
+                w.put_varint(b.transactions.len() as u64);
+                for tx in &b.transactions {
+                    wire::write_tx(&mut w, tx);
+                }
+            }
+            Message::Tx(tx) => {
+                w.put_u8(5);
+                wire::write_tx(&mut w, tx);
+            }
+            Message::GetHeaders(locator) => {
+                w.put_u8(6);
+                w.put_varint(locator.len() as u64);
+                for hash in locator {
+                    w.put_hash(hash);
+                }
+            }
+            Message::Headers(headers) => {
+                w.put_u8(7);
+                w.put_varint(headers.len() as u64);
+                for header in headers {
+                    wire::write_header(&mut w, header);
+                }
+            }
+        }
+        w.buf
+    }
+
+    /// 区块和交易的 hash 用链的 hash 函数重新计算
+    pub fn decode(bytes: &[u8], hasher: Hasher) -> Result<Message> {
+        let mut r = Reader { buf: bytes };
+        let msg = match r.u8()? {
+            0 => Message::Version(Version {
+                protocol: r.u32()?,
+                network_id: r.u32()?,
+                genesis: r.array()?,
+                best_height: r.u64()?,
+                nonce: r.u64()?,
+            }),
+            1 => Message::Verack,
+            2 => Message::Inv(read_items(&mut r)?),
+            3 => Message::GetData(read_items(&mut r)?),
+            4 => {
+                let b = wire::decode_block(r.buf, hasher)?;
+                r.buf = &[];
+                Message::Block(b)
+            }
+            5 => Message::Tx(wire::read_tx(&mut r, hasher)?),
+            6 => {
+                let n = read_count(&mut r, MAX_LOCATOR)?;
+                let mut locator = Vec::with_capacity(n);
+                for _ in 0..n {
+                    locator.push(r.array()?);
+                }
+                Message::GetHeaders(locator)
+            }
+            7 => {
+                let n = read_count(&mut r, MAX_HEADERS)?;
+                let mut headers = Vec::with_capacity(n);
+                for _ in 0..n {
+                    headers.push(wire::read_header(&mut r)?);
+                }
+                Message::Headers(headers)
+            }
+            c => return Err(Error::Codec(format!("unknown command {}", c))),
+        };
+        r.finish()?;
+        Ok(msg)
+    }
+}
+
+/// 写入一帧
+pub fn write_message<W: Write>(w: &mut W, msg: &Message) -> Result<()> {
+    let body = msg.encode();
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&body);
+    w.write_all(&frame).map_err(net_err)?;
+    w.flush().map_err(net_err)
+}
+
+/// 读取一帧，超过 max_block_size 的帧不读取内容，直接返回错误
+pub fn read_message<R: Read>(r: &mut R, max_block_size: usize, hasher: Hasher) -> Result<Message> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len).map_err(net_err)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > max_block_size + MAX_OTHER_SIZE {
+        return Err(Error::Network(format!("message too large: {}", len)));
+    }
+    let mut body = vec![0u8; len];
+    r.read_exact(&mut body).map_err(net_err)?;
+    Message::decode(&body, hasher)
+}
+
+pub(crate) fn net_err(e: std::io::Error) -> Error {
+    Error::Network(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_message, write_message, InvItem, Message, Version};
+    use crate::core::block::Block;
+    use crate::core::transaction::Transaction;
+    use crate::error::Error;
+    use crate::utils::coder::Hasher;
+
+    #[test]
+    fn message_works() {
+        let h = Hasher::default();
+        let b = Block::new(
+            vec![Transaction::new_coinbase([1; 32], 50, b"x", h)],
+            [2; 32],
+            0x2100FFFF,
+            1,
+            h,
+        );
+        let msgs = vec![
+            Message::Version(Version {
+                protocol: 1,
+                network_id: 2,
+                genesis: [3; 32],
+                best_height: 4,
+                nonce: 5,
+            }),
+            Message::Verack,
+            Message::Inv(vec![InvItem::block([6; 32]), InvItem::tx([7; 32])]),
+            Message::GetData(vec![]),
+            Message::Block(b.clone()),
+            Message::Tx(b.transactions[0].clone()),
+            Message::GetHeaders(vec![[8; 32], [9; 32]]),
+            Message::Headers(vec![b.header.clone()]),
+        ];
+
+        let mut buf = Vec::new();
+        for msg in &msgs {
+            write_message(&mut buf, msg).unwrap();
+        }
+        let mut r = &buf[..];
+        for msg in &msgs {
+            let decoded = read_message(&mut r, 1024, h).unwrap();
+            assert_eq!(decoded.encode(), msg.encode());
+        }
+        match read_message(&mut &buf[..4], 1024, h) {
+            Err(Error::Network(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // 太大的帧和不认识的 command
+        let big = (1u32 << 30).to_le_bytes();
+        assert!(read_message(&mut &big[..], 1024, h).is_err());
+        assert!(Message::decode(&[9], h).is_err());
+        assert!(Message::decode(&[1, 0], h).is_err());
+    }
+}
@@ -0,0 +1,790 @@
+/// p2p 节点：TCP 连接，交换区块和交易
+///
+/// - 每个连接一个线程读取消息，写入时锁住该连接，所有线程共享同一个 Host
+/// - 握手时检查协议版本、network_id 和 genesis ，连接到自己时断开
+/// - 新的区块和交易用 Inv 通知还不知道它的节点，对方用 GetData 请求
+/// - 连接数不超过 ChainConfig.max_peers ，对方发送无法解码的消息或不合法的区块时断开
+///
+/// pow 期间不锁住 Host ，收到的区块改变了 tail 时中断正在进行的 pow 。
+/// 落后的节点用区块头优先的方式同步，见 sync.rs 。
+///
+use crate::core::block::{Block, BlockHeader};
+use crate::core::miner::{Host, Miner};
+use crate::core::transaction::Transaction;
+use crate::core::validation::BlockError;
+use crate::error::{Error, Result};
+use crate::net::message::{
+    net_err, read_message, write_message, InvItem, InvKind, Message, Version, MAX_HEADERS,
+    PROTOCOL_VERSION,
+};
+use crate::net::sync::{SyncState, SyncStatus, BLOCK_WINDOW};
+use crate::utils::coder::Hasher;
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::thread;
+use std::time::Duration;
+
+/// 握手必须在这个时间内完成
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// 对方长时间不读取时放弃写入，避免阻塞其他线程
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// 没有新连接时 accept 的轮询间隔
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+/// 每个连接最多记住的对方已知的 hash
+const MAX_KNOWN: usize = 10_000;
+/// 同步线程检查的间隔
+const SYNC_INTERVAL: Duration = Duration::from_millis(100);
+/// 请求区块头后等待的时间，超时断开
+const HEADERS_TIMEOUT: Duration = Duration::from_secs(30);
+/// 请求区块后等待的时间，超时重新分配给其他节点
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 连接的信息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    /// 由本节点发起的连接
+    pub outbound: bool,
+    /// 握手时对方的高度，之后随收到的区块更新
+    pub best_height: u64,
+}
+
+struct Peer {
+    id: u64,
+    addr: SocketAddr,
+    outbound: bool,
+    best_height: AtomicU64,
+    stream: Mutex<TcpStream>,
+    /// 对方已经知道的区块和交易，不再重复通知
+    known: Mutex<HashSet<[u8; 32]>>,
+}
+
+impl Peer {
+    /// 写入失败时关闭连接，读取线程随之退出
+    fn send(&self, msg: &Message) -> Result<()> {
+        let mut stream = self.stream.lock().unwrap_or_else(|e| e.into_inner());
+        let r = write_message(&mut *stream, msg);
+        if r.is_err() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        r
+    }
+
+    /// 第一次记录时返回 true
+    fn mark_known(&self, hash: &[u8; 32]) -> bool {
+        let mut known = self.known.lock().unwrap_or_else(|e| e.into_inner());
+        if known.len() >= MAX_KNOWN {
+            known.clear();
+        }
+        known.insert(*hash)
+    }
+
+    fn best_height(&self) -> u64 {
+        self.best_height.load(Ordering::Relaxed)
+    }
+
+    fn info(&self) -> PeerInfo {
+        PeerInfo {
+            addr: self.addr,
+            outbound: self.outbound,
+            best_height: self.best_height(),
+        }
+    }
+}
+
+struct Inner {
+    host: Mutex<Host>,
+    /// 和 Host 中的 Miner 共享中断标记，pow 时不用锁住 Host
+    miner: Miner,
+    network_id: u32,
+    genesis: [u8; 32],
+    max_peers: usize,
+    max_block_size: usize,
+    /// 解码消息时重新计算区块和交易的 hash
+    hasher: Hasher,
+    /// 本节点的随机数，握手时收到相同的说明连接到了自己
+    nonce: u64,
+    local_addr: SocketAddr,
+    peers: Mutex<HashMap<u64, Arc<Peer>>>,
+    /// 正在握手的连接，和 peers 一起计入 max_peers ，只在锁住 peers 时修改
+    handshakes: AtomicUsize,
+    next_id: AtomicU64,
+    /// 同时需要时先锁住 sync 再锁住 host
+    sync: Mutex<SyncState>,
+    shutdown: AtomicBool,
+}
+
+pub struct Node {
+    inner: Arc<Inner>,
+}
+
+impl Node {
+    /// 监听 ChainConfig.listen_addr ，连接 ChainConfig.peers 中的节点，连接失败只打印错误
+    pub fn start(host: Host) -> Result<Node> {
+        let config = host.blockchain().config().clone();
+        let listener = TcpListener::bind(&config.listen_addr).map_err(net_err)?;
+        listener.set_nonblocking(true).map_err(net_err)?;
+
+        let inner = Arc::new(Inner {
+            miner: host.miner().clone(),
+            network_id: config.network_id,
+            genesis: host.blockchain().genesis_hash,
+            max_peers: config.max_peers,
+            max_block_size: config.max_block_size,
+            hasher: config.hasher,
+            nonce: rand::random(),
+            local_addr: listener.local_addr().map_err(net_err)?,
+            host: Mutex::new(host),
+            peers: Mutex::new(HashMap::new()),
+            handshakes: AtomicUsize::new(0),
+            next_id: AtomicU64::new(0),
+            sync: Mutex::new(SyncState::new()),
+            shutdown: AtomicBool::new(false),
+        });
+        let accept = inner.clone();
+        thread::spawn(move || accept.accept_loop(listener));
+        let sync = inner.clone();
+        thread::spawn(move || sync.sync_loop());
+
+        let node = Node { inner };
+        for addr in &config.peers {
+            if let Err(e) = node.connect(addr) {
+                eprintln!("connect {}: {}", addr, e);
+            }
+        }
+        Ok(node)
+    }
+
+    /// 连接并完成握手后返回
+    pub fn connect(&self, addr: &str) -> Result<()> {
+        if !self.inner.reserve() {
+            return Err(Error::Network("too many peers".to_string()));
+        }
+        let stream = match TcpStream::connect(addr) {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.inner.release();
+                return Err(net_err(e));
+            }
+        };
+        let peer = self.inner.handshake(&stream, true)?;
+        let inner = self.inner.clone();
+        thread::spawn(move || inner.run(peer, stream));
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.inner.peers().iter().map(|p| p.info()).collect()
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.inner.peer_count()
+    }
+
+    pub fn host(&self) -> MutexGuard<'_, Host> {
+        self.inner.host()
+    }
+
+    pub fn sync_status(&self) -> Result<SyncStatus> {
+        let sync = self.inner.sync();
+        let host = self.host();
+        let (_, best) = host.blockchain().best_header()?;
+        Ok(SyncStatus {
+            height: host.blockchain().curr_height,
+            best_header_height: best.height,
+            in_flight: sync.in_flight(),
+            syncing: sync.is_syncing(),
+        })
+    }
+
+    /// 放入交易池并通知其他节点
+    pub fn submit_transaction(&self, tx: Transaction) -> Result<()> {
+        let hash = tx.hash;
+        self.host().add_transaction(tx)?;
+        self.inner.broadcast(InvItem::tx(hash), None);
+        Ok(())
+    }
+
+    /// 出块并通知其他节点，返回成为新的 tail 的区块 hash ，被收到的区块中断时返回 None
+    ///
+    /// 只在组装区块和加入区块时锁住 Host ，pow 期间其他线程可以处理收到的区块
+    pub fn mine(&self) -> Result<Option<[u8; 32]>> {
+        let template = self.host().block_template()?;
+        let b = match self.inner.miner.work(template) {
+            Some(b) => b,
+            None => return Ok(None),
+        };
+        let hash = b.hash;
+        let mut host = self.host();
+        host.input_block(b)?;
+        let tail = host.blockchain().curr_hash;
+        drop(host);
+
+        // pow 结束后 tail 已经被收到的区块改变，新区块只是分叉
+        if tail != hash {
+            return Ok(None);
+        }
+        self.inner.broadcast(InvItem::block(hash), None);
+        Ok(Some(hash))
+    }
+
+    /// 停止监听并断开所有连接
+    pub fn shutdown(&self) {
+        self.inner.shutdown.store(true, Ordering::Relaxed);
+        for peer in self.inner.peers() {
+            self.inner.disconnect(&peer);
+        }
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl Inner {
+    fn host(&self) -> MutexGuard<'_, Host> {
+        self.host.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn sync(&self) -> MutexGuard<'_, SyncState> {
+        self.sync.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn peers(&self) -> Vec<Arc<Peer>> {
+        let peers = self.peers.lock().unwrap_or_else(|e| e.into_inner());
+        peers.values().cloned().collect()
+    }
+
+    fn peer_count(&self) -> usize {
+        self.peers.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// 为握手预留一个连接，已满时返回 false ，handshake 结束时释放
+    fn reserve(&self) -> bool {
+        let peers = self.peers.lock().unwrap_or_else(|e| e.into_inner());
+        if peers.len() + self.handshakes.load(Ordering::Relaxed) >= self.max_peers {
+            return false;
+        }
+        self.handshakes.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    fn release(&self) {
+        let _peers = self.peers.lock().unwrap_or_else(|e| e.into_inner());
+        self.handshakes.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn accept_loop(self: Arc<Self>, listener: TcpListener) {
+        while !self.shutdown.load(Ordering::Relaxed) {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_INTERVAL);
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("accept: {}", e);
+                    thread::sleep(ACCEPT_INTERVAL);
+                    continue;
+                }
+            };
+            // 已满时直接关闭
+            if !self.reserve() {
+                continue;
+            }
+            let inner = self.clone();
+            thread::spawn(move || {
+                if stream.set_nonblocking(false).is_err() {
+                    inner.release();
+                    return;
+                }
+                match inner.handshake(&stream, false) {
+                    Ok(peer) => inner.run(peer, stream),
+                    Err(e) => eprintln!("handshake: {}", e),
+                }
+            });
+        }
+    }
+
+    /// 调用前先 reserve ，无论成功与否都释放预留的连接，成功时加入 peers
+    fn handshake(&self, stream: &TcpStream, outbound: bool) -> Result<Arc<Peer>> {
+        let r = self.exchange_versions(stream, outbound);
+        let mut peers = self.peers.lock().unwrap_or_else(|e| e.into_inner());
+        self.handshakes.fetch_sub(1, Ordering::Relaxed);
+        let peer = r?;
+        peers.insert(peer.id, peer.clone());
+        println!("peer connected: {}", peer.addr);
+
+        Ok(peer)
+    }
+
+    /// 双方都先发送 Version ，检查对方的 Version 后回复 Verack
+    fn exchange_versions(&self, stream: &TcpStream, outbound: bool) -> Result<Arc<Peer>> {
+        let addr = stream.peer_addr().map_err(net_err)?;
+        stream
+            .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+            .map_err(net_err)?;
+        stream
+            .set_write_timeout(Some(WRITE_TIMEOUT))
+            .map_err(net_err)?;
+        let mut s = stream;
+
+        let version = Version {
+            protocol: PROTOCOL_VERSION,
+            network_id: self.network_id,
+            genesis: self.genesis,
+            best_height: self.host().blockchain().curr_height,
+            nonce: self.nonce,
+        };
+        write_message(&mut s, &Message::Version(version))?;
+        let remote = match read_message(&mut s, self.max_block_size, self.hasher)? {
+            Message::Version(v) => v,
+            _ => return Err(Error::Network("expected version".to_string())),
+        };
+        if remote.protocol != PROTOCOL_VERSION {
+            return Err(Error::Network(format!(
+                "unsupported protocol {}",
+                remote.protocol
+            )));
+        }
+        if remote.network_id != self.network_id || remote.genesis != self.genesis {
+            return Err(Error::Network(format!(
+                "different network: {}",
+                remote.network_id
+            )));
+        }
+        if remote.nonce == self.nonce {
+            return Err(Error::Network("connected to self".to_string()));
+        }
+        write_message(&mut s, &Message::Verack)?;
+        match read_message(&mut s, self.max_block_size, self.hasher)? {
+            Message::Verack => {}
+            _ => return Err(Error::Network("expected verack".to_string())),
+        }
+        stream.set_read_timeout(None).map_err(net_err)?;
+
+        Ok(Arc::new(Peer {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            addr,
+            outbound,
+            best_height: AtomicU64::new(remote.best_height),
+            stream: Mutex::new(stream.try_clone().map_err(net_err)?),
+            known: Mutex::new(HashSet::new()),
+        }))
+    }
+
+    /// 读取消息直到连接关闭或出错
+    fn run(&self, peer: Arc<Peer>, mut stream: TcpStream) {
+        let tail = self.host().blockchain().curr_hash;
+        let mut r = peer.send(&Message::Inv(vec![InvItem::block(tail)]));
+        while r.is_ok() && !self.shutdown.load(Ordering::Relaxed) {
+            r = read_message(&mut stream, self.max_block_size, self.hasher)
+                .and_then(|msg| self.handle(&peer, msg));
+        }
+        if let Err(e) = r {
+            println!("peer disconnected: {}: {}", peer.addr, e);
+        }
+        self.disconnect(&peer);
+    }
+
+    fn disconnect(&self, peer: &Peer) {
+        let stream = peer.stream.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = stream.shutdown(Shutdown::Both);
+        let mut peers = self.peers.lock().unwrap_or_else(|e| e.into_inner());
+        peers.remove(&peer.id);
+    }
+
+    fn has_item(host: &Host, item: &InvItem) -> Result<bool> {
+        let chain = host.blockchain();
+        Ok(match item.kind {
+            InvKind::Block => chain.has_block(&item.hash)?,
+            InvKind::Tx => {
+                host.mempool().contains(&item.hash) || chain.get_tx_location(&item.hash)?.is_some()
+            }
+        })
+    }
+
+    fn get_item(&self, item: &InvItem) -> Result<Option<Message>> {
+        let host = self.host();
+        Ok(match item.kind {
+            InvKind::Block => host.blockchain().get_block(&item.hash)?.map(Message::Block),
+            InvKind::Tx => match host.mempool().get(&item.hash) {
+                Some(tx) => Some(Message::Tx(tx.clone())),
+                None => host
+                    .blockchain()
+                    .get_transaction(&item.hash)?
+                    .map(Message::Tx),
+            },
+        })
+    }
+
+    /// 返回错误时断开连接
+    fn handle(&self, peer: &Peer, msg: Message) -> Result<()> {
+        match msg {
+            Message::Version(_) | Message::Verack => {
+                return Err(Error::Network("unexpected handshake message".to_string()))
+            }
+            Message::Inv(items) => {
+                // Host 被其他线程锁住时不等待，全部请求，重复收到的区块和交易会被忽略
+                let host = match self.host.try_lock() {
+                    Ok(host) => Some(host),
+                    Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+                    Err(TryLockError::WouldBlock) => None,
+                };
+                let mut wanted = Vec::new();
+                for item in items {
+                    peer.mark_known(&item.hash);
+                    let known = match &host {
+                        Some(host) => Self::has_item(host, &item)?,
+                        None => false,
+                    };
+                    if !known {
+                        wanted.push(item);
+                    }
+                }
+                drop(host);
+                if !wanted.is_empty() {
+                    peer.send(&Message::GetData(wanted))?;
+                }
+            }
+            Message::GetData(items) => {
+                // 逐个读出并发送，写入时不锁住 Host
+                for item in items {
+                    if let Some(msg) = self.get_item(&item)? {
+                        peer.send(&msg)?;
+                    }
+                }
+            }
+            Message::Block(b) => {
+                peer.mark_known(&b.hash);
+                peer.best_height
+                    .fetch_max(b.header.height, Ordering::Relaxed);
+                self.input_block(peer, b)?;
+            }
+            Message::Tx(tx) => {
+                peer.mark_known(&tx.hash);
+                let hash = tx.hash;
+                let r = self.host().add_transaction(tx);
+                match r {
+                    Ok(()) => self.broadcast(InvItem::tx(hash), Some(peer.id)),
+                    Err(Error::Mempool(_)) => {}
+                    Err(e) => eprintln!("add transaction: {}", e),
+                }
+            }
+            Message::GetHeaders(locator) => {
+                let headers = self
+                    .host()
+                    .blockchain()
+                    .headers_after(&locator, MAX_HEADERS)?;
+                peer.send(&Message::Headers(headers))?;
+            }
+            Message::Headers(headers) => self.input_headers(peer, headers)?,
+        }
+        Ok(())
+    }
+
+    /// 父区块未知时：请求过的区块等待父区块，否则说明落后不止一个区块，向对方请求区块头
+    /// 加入后继续加入等待它的区块，tail 变化并且不在同步中时通知其他节点
+    fn input_block(&self, peer: &Peer, b: Block) -> Result<()> {
+        let mut sync = self.sync();
+        let requested = sync.received(&b.hash);
+        let mut host = self.host();
+        if !host.blockchain().has_block(&b.header.pre_hash)? {
+            if requested {
+                sync.wait_for_parent(b);
+            } else if sync.headers_peer().is_none() {
+                sync.start_headers(peer.id);
+                let locator = host.blockchain().locator()?;
+                drop(host);
+                drop(sync);
+                peer.send(&Message::GetHeaders(locator))?;
+            }
+            return Ok(());
+        }
+
+        let before = host.blockchain().curr_hash;
+        let mut next = Some(b);
+        while let Some(b) = next {
+            let hash = b.hash;
+            let r = host.input_block(b);
+            match r {
+                Ok(()) | Err(Error::Validation(BlockError::AlreadyKnown)) => {}
+                Err(Error::Validation(e)) => {
+                    return Err(Error::Network(format!("invalid block: {}", e)))
+                }
+                Err(e) => {
+                    eprintln!("input block: {}", e);
+                    break;
+                }
+            }
+            next = sync.take_child(&hash);
+        }
+        let tail = host.blockchain().curr_hash;
+        let syncing = sync.is_syncing();
+        drop(host);
+        drop(sync);
+
+        // 区块验证通过并改变了 tail ，正在进行的 pow 已经没有意义
+        if tail != before {
+            self.miner.cancel();
+        }
+
+        if tail != before && !syncing {
+            self.broadcast(InvItem::block(tail), Some(peer.id));
+        }
+        Ok(())
+    }
+
+    /// 收到 MAX_HEADERS 个区块头时继续向同一个节点请求，否则区块头下载完成
+    fn input_headers(&self, peer: &Peer, headers: Vec<BlockHeader>) -> Result<()> {
+        let mut sync = self.sync();
+        let host = self.host();
+        let chain = host.blockchain();
+        if let Some(last) = headers.last() {
+            peer.best_height.fetch_max(last.height, Ordering::Relaxed);
+        }
+        let more = match chain.input_headers(&headers) {
+            Ok(_) => {
+                sync.set_stale();
+                headers.len() == MAX_HEADERS
+            }
+            // 对方的主链在两次请求之间发生了变化
+            Err(Error::Validation(BlockError::UnknownParent)) => false,
+            Err(Error::Validation(e)) => {
+                return Err(Error::Network(format!("invalid header: {}", e)))
+            }
+            Err(e) => return Err(e),
+        };
+        if sync.headers_peer() != Some(peer.id) {
+            return Ok(());
+        }
+
+        match headers.last() {
+            Some(last) if more => {
+                sync.start_headers(peer.id);
+                drop(host);
+                drop(sync);
+                peer.send(&Message::GetHeaders(vec![last.hash(self.hasher)]))?;
+            }
+            _ => {
+                sync.finish_headers();
+                // 对方没有更多的区块头，不再向它请求
+                let (_, best) = chain.best_header()?;
+                peer.best_height.fetch_min(best.height, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    fn sync_loop(self: Arc<Self>) {
+        while !self.shutdown.load(Ordering::Relaxed) {
+            thread::sleep(SYNC_INTERVAL);
+            if let Err(e) = self.sync_tick() {
+                eprintln!("sync: {}", e);
+            }
+        }
+    }
+
+    /// 请求区块头、分配区块体的下载、处理超时、打印进度
+    fn sync_tick(&self) -> Result<()> {
+        let peers = self.peers();
+        let connected: HashSet<u64> = peers.iter().map(|p| p.id).collect();
+        let mut requests = Vec::new();
+        let mut stalled = None;
+
+        let mut sync = self.sync();
+        sync.expire(&connected, BLOCK_TIMEOUT);
+        let host = self.host();
+        let chain = host.blockchain();
+        let (_, best) = chain.best_header()?;
+
+        if let Some(id) = sync.headers_timed_out(HEADERS_TIMEOUT) {
+            stalled = peers.iter().find(|p| p.id == id).cloned();
+            sync.finish_headers();
+        }
+        if !matches!(sync.headers_peer(), Some(id) if connected.contains(&id)) {
+            sync.finish_headers();
+            let taller = peers
+                .iter()
+                .filter(|p| p.best_height() > best.height)
+                .max_by_key(|p| p.best_height());
+            if let Some(peer) = taller {
+                sync.start_headers(peer.id);
+                requests.push((peer.clone(), Message::GetHeaders(chain.locator()?)));
+            }
+        }
+
+        if sync.needs_refill() {
+            sync.refill(chain.missing_blocks()?);
+        }
+        let limit = chain.curr_height + BLOCK_WINDOW;
+        for peer in &peers {
+            let hashes = sync.assign(peer.id, peer.best_height(), limit);
+            if !hashes.is_empty() {
+                let items = hashes.into_iter().map(InvItem::block).collect();
+                requests.push((peer.clone(), Message::GetData(items)));
+            }
+        }
+
+        if sync.is_syncing() && sync.should_report() {
+            println!(
+                "sync: height {}, headers {}, {} blocks in flight",
+                chain.curr_height,
+                best.height,
+                sync.in_flight()
+            );
+        }
+        drop(host);
+        drop(sync);
+
+        if let Some(peer) = stalled {
+            println!("peer stalled: {}", peer.addr);
+            self.disconnect(&peer);
+        }
+        for (peer, msg) in requests {
+            let _ = peer.send(&msg);
+        }
+        Ok(())
+    }
+
+    /// 通知除了 except 之外还不知道它的节点
+    fn broadcast(&self, item: InvItem, except: Option<u64>) {
+        for peer in self.peers() {
+            if Some(peer.id) != except && peer.mark_known(&item.hash) {
+                let _ = peer.send(&Message::Inv(vec![item]));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Node;
+    use crate::core::account::Account;
+    use crate::core::blockchain::BlockChain;
+    use crate::core::config::ChainConfig;
+    use crate::core::miner::Host;
+    use crate::core::storage::MemoryStorage;
+    use crate::utils::testing::wait_until;
+    use std::net::TcpStream;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    fn start(network_id: u32, max_peers: usize) -> Node {
+        let config = ChainConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            network_id,
+            max_peers,
+            ..ChainConfig::default()
+        };
+        let chain = BlockChain::with_storage(Arc::new(MemoryStorage::new()), config).unwrap();
+        Node::start(Host::with_blockchain(chain)).unwrap()
+    }
+
+    #[test]
+    fn gossip_works() {
+        let a = start(0, 8);
+        let b = start(0, 8);
+        let c = start(0, 8);
+        b.connect(&a.local_addr().to_string()).unwrap();
+        c.connect(&b.local_addr().to_string()).unwrap();
+        wait_until(|| a.peer_count() == 1 && b.peer_count() == 2 && c.peer_count() == 1);
+        assert!(b.peers().iter().any(|p| p.outbound));
+        assert!(b.peers().iter().any(|p| !p.outbound));
+
+        // 区块经过 b 转发到 c
+        assert!(a.mine().unwrap().is_some());
+        let tail = a.host().blockchain().curr_hash;
+        wait_until(|| c.host().blockchain().curr_hash == tail);
+
+        // 交易经过 b 转发到 a
+        let mut alice = Account::generate();
+        let tx = alice
+            .send_to([3; 32], 0, 0, ChainConfig::default().hasher)
+            .unwrap();
+        c.submit_transaction(tx.clone()).unwrap();
+        wait_until(|| a.host().mempool().contains(&tx.hash));
+
+        // 打包后其他节点的交易池中也删除
+        assert!(a.mine().unwrap().is_some());
+        let tail = a.host().blockchain().curr_hash;
+        wait_until(|| c.host().blockchain().curr_hash == tail);
+        assert!(!c.host().mempool().contains(&tx.hash));
+        assert!(c
+            .host()
+            .blockchain()
+            .get_tx_location(&tx.hash)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn sync_works() {
+        let a = start(0, 8);
+        for _ in 0..30 {
+            assert!(a.mine().unwrap().is_some());
+        }
+        let tail = a.host().blockchain().curr_hash;
+
+        // b 从 a 同步，c 同时从 a 和 b 下载
+        let b = start(0, 8);
+        b.connect(&a.local_addr().to_string()).unwrap();
+        wait_until(|| b.host().blockchain().curr_hash == tail);
+        let c = start(0, 8);
+        c.connect(&a.local_addr().to_string()).unwrap();
+        c.connect(&b.local_addr().to_string()).unwrap();
+        wait_until(|| c.host().blockchain().curr_hash == tail);
+        wait_until(|| !c.sync_status().unwrap().syncing);
+        let status = c.sync_status().unwrap();
+        assert_eq!(status.height, 30);
+        assert_eq!(status.best_header_height, 30);
+        assert_eq!(status.in_flight, 0);
+
+        // 之后的区块直接转发
+        assert!(b.mine().unwrap().is_some());
+        let tail = b.host().blockchain().curr_hash;
+        wait_until(|| a.host().blockchain().curr_hash == tail);
+        wait_until(|| c.host().blockchain().curr_hash == tail);
+    }
+
+    #[test]
+    fn handshake_works() {
+        let a = start(0, 1);
+        let addr = a.local_addr().to_string();
+
+        let handshakes = || a.inner.handshakes.load(Ordering::Relaxed);
+
+        // 不同的网络和自己都不能连接
+        assert!(start(1, 8).connect(&addr).is_err());
+        assert!(a.connect(&addr).is_err());
+        wait_until(|| handshakes() == 0);
+
+        // 没有完成握手的连接也占用名额，断开后释放
+        let raw = TcpStream::connect(&addr).unwrap();
+        wait_until(|| handshakes() == 1);
+        assert!(start(0, 8).connect(&addr).is_err());
+        drop(raw);
+        wait_until(|| handshakes() == 0);
+
+        let b = start(0, 8);
+        b.connect(&addr).unwrap();
+        wait_until(|| a.peer_count() == 1);
+        assert_eq!(a.peers()[0].best_height, 0);
+
+        // 超过 max_peers
+        assert!(start(0, 8).connect(&addr).is_err());
+        assert_eq!(a.peer_count(), 1);
+
+        drop(b);
+        wait_until(|| a.peer_count() == 0);
+    }
+}
@@ -0,0 +1,215 @@
+/// 区块头优先的同步
+///
+/// 1. 有节点的高度超过本节点的 best_header 时，用 locator 向它请求区块头（GetHeaders），
+///    对方返回主链上之后的最多 MAX_HEADERS 个区块头
+/// 2. 区块头用 BlockChain::input_headers 检查 pow 、难度和时间后写入数据库，
+///    收到 MAX_HEADERS 个时继续请求
+/// 3. 从 best_header 往回找到缺少区块体的区块，按高度分配给高度足够的节点并行下载，
+///    每个节点最多 MAX_IN_FLIGHT 个，不超过 tail 之后 BLOCK_WINDOW 个
+/// 4. 区块体用 BlockChain::input_block 加入，父区块还没有加入的区块先等待
+///
+/// 区块头写在数据库中，重启后从 best_header 继续下载区块体。
+/// 超时的请求和断开的节点的请求重新分配给其他节点。
+///
+use crate::core::block::Block;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// 每个节点同时请求的区块数
+pub const MAX_IN_FLIGHT: usize = 16;
+/// 只下载 tail 之后这么多个区块，限制等待父区块的区块占用的内存
+pub const BLOCK_WINDOW: u64 = 1024;
+/// 打印进度的间隔
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 同步的进度
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncStatus {
+    /// tail 的高度
+    pub height: u64,
+    /// 已经下载的区块头的最大高度
+    pub best_header_height: u64,
+    /// 已经请求、还没有收到的区块数
+    pub in_flight: usize,
+    /// 正在下载区块头或区块体
+    pub syncing: bool,
+}
+
+#[derive(Default)]
+pub(crate) struct SyncState {
+    /// 正在下载区块头的节点和请求时间
+    headers_peer: Option<(u64, Instant)>,
+    /// 等待下载的区块，height -> hash
+    queue: BTreeMap<u64, [u8; 32]>,
+    /// 已经请求的区块，hash -> (height, 节点, 请求时间)
+    in_flight: HashMap<[u8; 32], (u64, u64, Instant)>,
+    /// 父区块还没有加入的区块，pre_hash -> 区块
+    waiting: HashMap<[u8; 32], Block>,
+    /// 下载了新的区块头，queue 需要重新计算
+    stale: bool,
+    last_report: Option<Instant>,
+}
+
+impl SyncState {
+    /// 启动时从数据库中已有的区块头继续
+    pub(crate) fn new() -> SyncState {
+        SyncState {
+            stale: true,
+            ..SyncState::default()
+        }
+    }
+
+    pub(crate) fn headers_peer(&self) -> Option<u64> {
+        self.headers_peer.map(|(id, _)| id)
+    }
+
+    /// 向 peer 请求区块头，继续请求时刷新时间
+    pub(crate) fn start_headers(&mut self, peer: u64) {
+        self.headers_peer = Some((peer, Instant::now()));
+    }
+
+    pub(crate) fn finish_headers(&mut self) {
+        self.headers_peer = None;
+    }
+
+    /// 超过 timeout 没有回复区块头的节点
+    pub(crate) fn headers_timed_out(&self, timeout: Duration) -> Option<u64> {
+        match self.headers_peer {
+            Some((id, time)) if time.elapsed() > timeout => Some(id),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn set_stale(&mut self) {
+        self.stale = true;
+    }
+
+    /// 有新的区块头并且 queue 中的区块都已经分配
+    pub(crate) fn needs_refill(&self) -> bool {
+        self.stale && self.queue.is_empty()
+    }
+
+    /// missing 来自 BlockChain::missing_blocks ，跳过已经请求和等待父区块的区块
+    pub(crate) fn refill(&mut self, missing: Vec<([u8; 32], u64)>) {
+        let waiting: HashSet<[u8; 32]> = self.waiting.values().map(|b| b.hash).collect();
+        for (hash, height) in missing {
+            if !self.in_flight.contains_key(&hash) && !waiting.contains(&hash) {
+                self.queue.insert(height, hash);
+            }
+        }
+        self.stale = false;
+    }
+
+    /// 超时的请求和不在 connected 中的节点的请求放回 queue
+    pub(crate) fn expire(&mut self, connected: &HashSet<u64>, timeout: Duration) {
+        let queue = &mut self.queue;
+        self.in_flight.retain(|hash, (height, peer, time)| {
+            let keep = connected.contains(peer) && time.elapsed() <= timeout;
+            if !keep {
+                queue.insert(*height, *hash);
+            }
+            keep
+        });
+    }
+
+    /// 给 peer 分配高度不超过 best_height 和 limit 的区块，按高度从低到高
+    pub(crate) fn assign(&mut self, peer: u64, best_height: u64, limit: u64) -> Vec<[u8; 32]> {
+        let busy = self
+            .in_flight
+            .values()
+            .filter(|(_, p, _)| *p == peer)
+            .count();
+        let max_height = best_height.min(limit);
+        let heights: Vec<u64> = self
+            .queue
+            .range(..=max_height)
+            .take(MAX_IN_FLIGHT.saturating_sub(busy))
+            .map(|(height, _)| *height)
+            .collect();
+
+        let now = Instant::now();
+        let mut hashes = Vec::new();
+        for height in heights {
+            if let Some(hash) = self.queue.remove(&height) {
+                self.in_flight.insert(hash, (height, peer, now));
+                hashes.push(hash);
+            }
+        }
+        hashes
+    }
+
+    /// 收到区块，是请求过的区块时返回 true
+    pub(crate) fn received(&mut self, hash: &[u8; 32]) -> bool {
+        self.in_flight.remove(hash).is_some()
+    }
+
+    pub(crate) fn wait_for_parent(&mut self, b: Block) {
+        self.waiting.insert(b.header.pre_hash, b);
+    }
+
+    /// 等待 parent 的区块
+    pub(crate) fn take_child(&mut self, parent: &[u8; 32]) -> Option<Block> {
+        self.waiting.remove(parent)
+    }
+
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub(crate) fn is_syncing(&self) -> bool {
+        self.headers_peer.is_some()
+            || !self.queue.is_empty()
+            || !self.in_flight.is_empty()
+            || !self.waiting.is_empty()
+    }
+
+    /// 距离上次打印超过 REPORT_INTERVAL
+    pub(crate) fn should_report(&mut self) -> bool {
+        match self.last_report {
+            Some(time) if time.elapsed() < REPORT_INTERVAL => false,
+            _ => {
+                self.last_report = Some(Instant::now());
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SyncState, MAX_IN_FLIGHT};
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    #[test]
+    fn assign_works() {
+        let mut sync = SyncState::new();
+        assert!(sync.needs_refill());
+        let missing: Vec<([u8; 32], u64)> = (1..=40u8).map(|i| ([i; 32], i as u64)).collect();
+        sync.refill(missing.clone());
+        assert!(!sync.needs_refill());
+
+        // 不超过节点的高度和每个节点的上限
+        assert_eq!(sync.assign(1, 3, 100), vec![[1; 32], [2; 32], [3; 32]]);
+        let hashes = sync.assign(2, 100, 100);
+        assert_eq!(hashes.len(), MAX_IN_FLIGHT);
+        assert_eq!(hashes[0], [4; 32]);
+        assert!(sync.assign(2, 100, 100).is_empty());
+        assert!(sync.assign(3, 100, 19).is_empty());
+
+        // 已经请求的区块不会重新放进 queue
+        sync.set_stale();
+        sync.refill(missing);
+        assert_eq!(sync.assign(3, 100, 100)[0], [20; 32]);
+
+        assert!(sync.received(&[1; 32]));
+        assert!(!sync.received(&[1; 32]));
+
+        // 节点 2 断开，它的请求重新分配
+        let connected: HashSet<u64> = [1, 3].iter().copied().collect();
+        sync.expire(&connected, Duration::from_secs(60));
+        assert_eq!(sync.in_flight(), 2 + MAX_IN_FLIGHT);
+        assert_eq!(sync.assign(1, 100, 100)[0], [4; 32]);
+        assert!(sync.is_syncing());
+    }
+}
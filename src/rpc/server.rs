@@ -0,0 +1,642 @@
+/// HTTP JSON-RPC 2.0 服务
+///
+/// 每个 HTTP 连接处理一个 POST 请求后关闭，请求体是 JSON-RPC 请求或请求数组。
+/// 没有 id 的通知（notification）执行后不回复，全部是通知时响应体为空。
+/// 同时处理的连接数不超过 MAX_CONNECTIONS ，整个请求必须在 REQUEST_TIMEOUT 内读完。
+/// hash 、地址和原始交易都用十六进制字符串，金额等整数用 JSON 数字。
+///
+/// | 方法               | 参数                    | 结果                            |
+/// |--------------------|-------------------------|---------------------------------|
+/// | getBlockByHash     | [hash]                  | 区块，不存在时为 null           |
+/// | getBlockByHeight   | [height]                | 主链上的区块，不存在时为 null   |
+/// | getTransaction     | [hash]                  | 交易，未打包时 blockHash 为 null |
+/// | getBalance         | [address]               | tail 状态中的余额               |
+/// | getNonce           | [address]               | tail 状态中的 nonce             |
+/// | sendRawTransaction | [wire 编码的交易]       | 交易 hash                       |
+/// | getBestBlock       | []                      | tail 区块                       |
+/// | mine               | []                      | 新的 tail hash ，被中断时为 null |
+///
+/// ```text
+/// curl -d '{"jsonrpc":"2.0","id":1,"method":"getBlockByHeight","params":[1]}' http://127.0.0.1:7880
+/// ```
+///
+use crate::core::block::Block;
+use crate::core::transaction::Transaction;
+use crate::core::wire;
+use crate::error::{Error, Result};
+use crate::net::message::net_err;
+use crate::net::node::Node;
+use crate::utils::hex;
+use serde::Serialize;
+use serde_json::Value;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 请求体的最大字节数
+const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+/// 请求行和每个 header 的最大字节数
+const MAX_LINE_SIZE: usize = 8 * 1024;
+/// 读取整个请求的超时，避免对方缓慢发送长期占用连接
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// 写入响应的超时
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// 同时处理的最大连接数，已满时新的连接直接关闭
+const MAX_CONNECTIONS: usize = 64;
+/// 没有新连接时 accept 的轮询间隔
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+
+// JSON-RPC 2.0 的错误码
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// 链、交易池等返回的错误
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> RpcError {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(e: Error) -> Self {
+        RpcError {
+            code: SERVER_ERROR,
+            message: e.to_string(),
+        }
+    }
+}
+
+/// JSON-RPC 响应，result 和 error 只有一个
+#[derive(Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+impl Response {
+    fn result(id: Value, result: Value) -> Response {
+        Response {
+            jsonrpc: "2.0",
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    fn error(id: Value, e: RpcError) -> Response {
+        Response {
+            jsonrpc: "2.0",
+            id,
+            result: None,
+            error: Some(e),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TxJson {
+    hash: String,
+    from: String,
+    to: String,
+    amount: u64,
+    fee: u64,
+    nonce: u64,
+    sign: String,
+}
+
+impl From<&Transaction> for TxJson {
+    fn from(tx: &Transaction) -> Self {
+        TxJson {
+            hash: hex::encode(&tx.hash),
+            from: hex::encode(&tx.from),
+            to: hex::encode(&tx.to),
+            amount: tx.amount,
+            fee: tx.fee,
+            nonce: tx.nonce,
+            sign: hex::encode(&tx.sign),
+        }
+    }
+}
+
+/// getTransaction 的结果，未打包时 blockHash 为 null 并且没有 index
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TxLocationJson {
+    #[serde(flatten)]
+    tx: TxJson,
+    block_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BlockJson {
+    hash: String,
+    height: u64,
+    time: i64,
+    tx_hash: String,
+    pre_hash: String,
+    bits: u32,
+    nonce: u32,
+    state_root: String,
+    transactions: Vec<TxJson>,
+}
+
+impl From<&Block> for BlockJson {
+    fn from(b: &Block) -> Self {
+        let h = &b.header;
+        BlockJson {
+            hash: hex::encode(&b.hash),
+            height: h.height,
+            time: h.time,
+            tx_hash: hex::encode(&h.tx_hash),
+            pre_hash: hex::encode(&h.pre_hash),
+            bits: h.bits,
+            nonce: h.nonce,
+            state_root: hex::encode(&h.state_root),
+            transactions: b.transactions.iter().map(TxJson::from).collect(),
+        }
+    }
+}
+
+pub struct RpcServer {
+    local_addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    /// 正在处理的连接数
+    connections: Arc<AtomicUsize>,
+}
+
+impl RpcServer {
+    /// 监听 addr ，默认配置只监听 localhost（ChainConfig.rpc_addr）
+    pub fn start(node: Arc<Node>, addr: &str) -> Result<RpcServer> {
+        Self::listen(node, addr, MAX_CONNECTIONS)
+    }
+
+    fn listen(node: Arc<Node>, addr: &str, max_connections: usize) -> Result<RpcServer> {
+        let listener = TcpListener::bind(addr).map_err(net_err)?;
+        listener.set_nonblocking(true).map_err(net_err)?;
+        let server = RpcServer {
+            local_addr: listener.local_addr().map_err(net_err)?,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            connections: Arc::new(AtomicUsize::new(0)),
+        };
+
+        let shutdown = server.shutdown.clone();
+        let connections = server.connections.clone();
+        thread::spawn(move || {
+            while !shutdown.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    // 只有这个线程增加计数，检查后再增加不会超过上限
+                    Ok(_) if connections.load(Ordering::Relaxed) >= max_connections => {}
+                    Ok((stream, _)) => {
+                        connections.fetch_add(1, Ordering::Relaxed);
+                        let node = node.clone();
+                        let connections = connections.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = serve(&node, stream) {
+                                eprintln!("rpc: {}", e);
+                            }
+                            connections.fetch_sub(1, Ordering::Relaxed);
+                        });
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+                    Err(e) => {
+                        eprintln!("rpc accept: {}", e);
+                        thread::sleep(ACCEPT_INTERVAL);
+                    }
+                }
+            }
+        });
+
+        Ok(server)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// 停止接受新的连接
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for RpcServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// 每次读取前把超时设置为剩余的时间，超过 deadline 后读取失败
+struct DeadlineReader {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left == Duration::from_secs(0) {
+            return Err(io::Error::new(ErrorKind::TimedOut, "request timeout"));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+/// 读取一行，不包括结尾的 \r\n
+fn read_line<R: BufRead>(r: &mut R) -> Result<String> {
+    let mut line = Vec::new();
+    r.take(MAX_LINE_SIZE as u64)
+        .read_until(b'\n', &mut line)
+        .map_err(net_err)?;
+    if line.last() != Some(&b'\n') {
+        return Err(Error::Network("bad http request".to_string()));
+    }
+    let line = String::from_utf8(line).map_err(|e| Error::Network(e.to_string()))?;
+    Ok(line.trim_end().to_string())
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).map_err(net_err)
+}
+
+/// 处理一个 HTTP 请求
+fn serve(node: &Node, mut stream: TcpStream) -> Result<()> {
+    stream.set_nonblocking(false).map_err(net_err)?;
+    stream
+        .set_write_timeout(Some(WRITE_TIMEOUT))
+        .map_err(net_err)?;
+    let mut reader = BufReader::new(DeadlineReader {
+        stream: stream.try_clone().map_err(net_err)?,
+        deadline: Instant::now() + REQUEST_TIMEOUT,
+    });
+
+    let request_line = read_line(&mut reader)?;
+    let mut content_length = None;
+    loop {
+        let line = read_line(&mut reader)?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    if !request_line.starts_with("POST ") {
+        return respond(&mut stream, "405 Method Not Allowed", "");
+    }
+    let len = match content_length {
+        Some(len) if len <= MAX_BODY_SIZE => len,
+        Some(_) => return respond(&mut stream, "413 Payload Too Large", ""),
+        None => return respond(&mut stream, "411 Length Required", ""),
+    };
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).map_err(net_err)?;
+
+    let response = match serde_json::from_slice(&body) {
+        Ok(Value::Array(requests)) if !requests.is_empty() => {
+            let responses: Vec<Response> =
+                requests.iter().filter_map(|r| handle(node, r)).collect();
+            if responses.is_empty() {
+                Ok(String::new())
+            } else {
+                serde_json::to_string(&responses)
+            }
+        }
+        Ok(request) => match handle(node, &request) {
+            Some(response) => serde_json::to_string(&response),
+            None => Ok(String::new()),
+        },
+        Err(e) => serde_json::to_string(&Response::error(
+            Value::Null,
+            RpcError::new(PARSE_ERROR, &e.to_string()),
+        )),
+    }
+    .map_err(|e| Error::Codec(e.to_string()))?;
+    respond(&mut stream, "200 OK", &response)
+}
+
+/// 一个 JSON-RPC 请求的响应，通知返回 None
+///
+/// 不合法的请求不是通知，总是回复，id 未知时为 null
+fn handle(node: &Node, request: &Value) -> Option<Response> {
+    let id = request.get("id").cloned();
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) if request.get("jsonrpc").and_then(Value::as_str) == Some("2.0") => method,
+        _ => {
+            let e = RpcError::new(INVALID_REQUEST, "invalid request");
+            return Some(Response::error(id.unwrap_or(Value::Null), e));
+        }
+    };
+    let result = match request.get("params") {
+        Some(Value::Array(params)) => call(node, method, params),
+        None => call(node, method, &[]),
+        Some(_) => Err(RpcError::new(INVALID_PARAMS, "params must be an array")),
+    };
+
+    let id = id?;
+    Some(match result {
+        Ok(result) => Response::result(id, result),
+        Err(e) => Response::error(id, e),
+    })
+}
+
+fn param_hash(params: &[Value], i: usize) -> std::result::Result<[u8; 32], RpcError> {
+    params
+        .get(i)
+        .and_then(Value::as_str)
+        .and_then(hex::decode_32)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "expected 32 bytes hex"))
+}
+
+fn param_u64(params: &[Value], i: usize) -> std::result::Result<u64, RpcError> {
+    params
+        .get(i)
+        .and_then(Value::as_u64)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "expected unsigned integer"))
+}
+
+fn param_bytes(params: &[Value], i: usize) -> std::result::Result<Vec<u8>, RpcError> {
+    params
+        .get(i)
+        .and_then(Value::as_str)
+        .and_then(hex::decode)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "expected hex"))
+}
+
+fn to_value<T: Serialize>(v: T) -> std::result::Result<Value, RpcError> {
+    serde_json::to_value(v).map_err(|e| RpcError::new(SERVER_ERROR, &e.to_string()))
+}
+
+fn call(node: &Node, method: &str, params: &[Value]) -> std::result::Result<Value, RpcError> {
+    let result = match method {
+        "getBlockByHash" => {
+            let hash = param_hash(params, 0)?;
+            let b = node.host().blockchain().get_block(&hash)?;
+            to_value(b.as_ref().map(BlockJson::from))?
+        }
+        "getBlockByHeight" => {
+            let height = param_u64(params, 0)?;
+            let b = node.host().blockchain().get_block_by_height(height)?;
+            to_value(b.as_ref().map(BlockJson::from))?
+        }
+        "getTransaction" => {
+            let hash = param_hash(params, 0)?;
+            let host = node.host();
+            if let Some(tx) = host.mempool().get(&hash) {
+                return to_value(TxLocationJson {
+                    tx: tx.into(),
+                    block_hash: None,
+                    index: None,
+                });
+            }
+            let chain = host.blockchain();
+            match chain.get_tx_location(&hash)? {
+                Some((block_hash, index)) => {
+                    let tx = chain.get_transaction(&hash)?.ok_or_else(|| {
+                        Error::Storage(format!("transaction missing: {:?}", hash))
+                    })?;
+                    to_value(TxLocationJson {
+                        tx: (&tx).into(),
+                        block_hash: Some(hex::encode(&block_hash)),
+                        index: Some(index),
+                    })?
+                }
+                None => Value::Null,
+            }
+        }
+        "getBalance" => {
+            let address = param_hash(params, 0)?;
+            node.host().get_account(&address)?.balance.into()
+        }
+        "getNonce" => {
+            let address = param_hash(params, 0)?;
+            node.host().get_account(&address)?.nonce.into()
+        }
+        "sendRawTransaction" => {
+            let hasher = node.host().blockchain().config().hasher;
+            let tx = wire::decode_tx(&param_bytes(params, 0)?, hasher)
+                .map_err(|e| RpcError::new(INVALID_PARAMS, &e.to_string()))?;
+            let hash = tx.hash;
+            node.submit_transaction(tx)?;
+            hex::encode(&hash).into()
+        }
+        "getBestBlock" => {
+            let host = node.host();
+            let chain = host.blockchain();
+            let b = chain
+                .get_block(&chain.curr_hash)?
+                .ok_or_else(|| Error::Storage("tail missing".to_string()))?;
+            to_value(BlockJson::from(&b))?
+        }
+        "mine" => match node.mine()? {
+            Some(hash) => hex::encode(&hash).into(),
+            None => Value::Null,
+        },
+        _ => return Err(RpcError::new(METHOD_NOT_FOUND, "method not found")),
+    };
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RpcServer;
+    use crate::core::account::Account;
+    use crate::core::blockchain::BlockChain;
+    use crate::core::config::ChainConfig;
+    use crate::core::miner::Host;
+    use crate::core::storage::MemoryStorage;
+    use crate::core::wire;
+    use crate::net::node::Node;
+    use crate::utils::hex;
+    use crate::utils::testing::wait_until;
+    use serde_json::{json, Value};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    fn start_node() -> Arc<Node> {
+        let config = ChainConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            ..ChainConfig::default()
+        };
+        let chain = BlockChain::with_storage(Arc::new(MemoryStorage::new()), config).unwrap();
+        Arc::new(Node::start(Host::with_blockchain(chain)).unwrap())
+    }
+
+    /// 返回响应体
+    fn post_raw(addr: SocketAddr, body: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let request = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        body.to_string()
+    }
+
+    fn post(addr: SocketAddr, body: &str) -> Value {
+        serde_json::from_str(&post_raw(addr, body)).unwrap()
+    }
+
+    fn call(addr: SocketAddr, method: &str, params: &str) -> Value {
+        let body = format!(
+            r#"{{"jsonrpc":"2.0","id":7,"method":"{}","params":{}}}"#,
+            method, params
+        );
+        let response = post(addr, &body);
+        assert_eq!(response.get("id"), Some(&json!(7)));
+        match response.get("result") {
+            Some(result) => result.clone(),
+            None => response.get("error").unwrap().clone(),
+        }
+    }
+
+    #[test]
+    fn rpc_works() {
+        let miner = hex::encode(&ChainConfig::default().miner_address);
+        let server = RpcServer::start(start_node(), "127.0.0.1:0").unwrap();
+        let addr = server.local_addr();
+
+        let genesis = call(addr, "getBestBlock", "[]");
+        assert_eq!(genesis.get("height"), Some(&json!(0)));
+        let tail = call(addr, "mine", "[]");
+        let b = call(addr, "getBlockByHeight", "[1]");
+        assert_eq!(b.get("hash"), Some(&tail));
+        assert_eq!(b.get("preHash"), genesis.get("hash"));
+        let by_hash = call(addr, "getBlockByHash", &format!("[{}]", tail));
+        assert_eq!(by_hash, b);
+        assert_eq!(call(addr, "getBlockByHeight", "[5]"), Value::Null);
+        let params = format!(r#"["{}"]"#, miner);
+        assert_eq!(call(addr, "getBalance", &params), json!(50));
+
+        let mut alice = Account::generate();
+        let tx = alice
+            .send_to([3; 32], 0, 0, ChainConfig::default().hasher)
+            .unwrap();
+        let raw = format!(r#"["{}"]"#, hex::encode(&wire::encode_tx(&tx)));
+        let hash = call(addr, "sendRawTransaction", &raw);
+        assert_eq!(hash.as_str(), Some(hex::encode(&tx.hash).as_str()));
+        let pending = call(addr, "getTransaction", &format!("[{}]", hash));
+        assert_eq!(pending.get("blockHash"), Some(&Value::Null));
+
+        let tail = call(addr, "mine", "[]");
+        let mined = call(addr, "getTransaction", &format!("[{}]", hash));
+        assert_eq!(mined.get("blockHash"), Some(&tail));
+        assert_eq!(mined.get("index"), Some(&json!(1)));
+        let params = format!(r#"["{}"]"#, hex::encode(&alice.address));
+        assert_eq!(call(addr, "getNonce", &params), json!(1));
+
+        // 错误
+        let code = |e: Value| e.get("code").cloned();
+        assert_eq!(code(call(addr, "unknown", "[]")), Some(json!(-32601)));
+        assert_eq!(
+            code(call(addr, "getBalance", "[\"00\"]")),
+            Some(json!(-32602))
+        );
+        assert_eq!(
+            code(call(addr, "sendRawTransaction", &raw)),
+            Some(json!(-32000))
+        );
+        let response = post(addr, "{");
+        assert_eq!(
+            code(response.get("error").unwrap().clone()),
+            Some(json!(-32700))
+        );
+        // 不合法的数字
+        for body in &[
+            r#"{"jsonrpc":"2.0","id":01}"#,
+            r#"{"jsonrpc":"2.0","id":-0012}"#,
+        ] {
+            let response = post(addr, body);
+            assert_eq!(response["error"]["code"], json!(-32700));
+        }
+
+        // 批量请求
+        let body = r#"[{"jsonrpc":"2.0","id":1,"method":"getNonce","params":["00"]},{"jsonrpc":"2.0","id":2,"method":"getBestBlock"}]"#;
+        match post(addr, body) {
+            Value::Array(responses) => {
+                assert_eq!(responses.len(), 2);
+                assert!(responses[0].get("error").is_some());
+                assert_eq!(responses[1].get("result").unwrap().get("hash"), Some(&tail));
+            }
+            other => panic!("unexpected {}", other),
+        }
+
+        // 通知执行后不回复，批量请求中只回复有 id 的请求
+        let mine = r#"{"jsonrpc":"2.0","method":"mine"}"#;
+        assert_eq!(post_raw(addr, mine), "");
+        assert_eq!(call(addr, "getBestBlock", "[]")["height"], json!(3));
+        let bad = r#"{"jsonrpc":"2.0","method":"getNonce","params":["00"]}"#;
+        assert_eq!(post_raw(addr, &format!("[{},{}]", mine, bad)), "");
+        assert_eq!(call(addr, "getBestBlock", "[]")["height"], json!(4));
+        let body = format!(
+            r#"[{},{{"jsonrpc":"2.0","id":3,"method":"getNonce","params":["00"]}}]"#,
+            bad
+        );
+        match post(addr, &body) {
+            Value::Array(responses) => {
+                assert_eq!(responses.len(), 1);
+                assert_eq!(responses[0]["id"], json!(3));
+            }
+            other => panic!("unexpected {}", other),
+        }
+    }
+
+    #[test]
+    fn connection_limit_works() {
+        let server = RpcServer::listen(start_node(), "127.0.0.1:0", 1).unwrap();
+        let addr = server.local_addr();
+        let connections = || server.connections.load(Ordering::Relaxed);
+
+        // 不发送请求的连接占用名额，新的连接直接关闭
+        let idle = TcpStream::connect(addr).unwrap();
+        wait_until(|| connections() == 1);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let _ = stream.write_all(b"POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\n[]");
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        assert!(response.is_empty());
+
+        drop(idle);
+        wait_until(|| connections() == 0);
+        assert_eq!(call(addr, "getNonce", r#"["00"]"#)["code"], json!(-32602));
+    }
+}
@@ -1,35 +1,97 @@
-use bincode;
+use crate::error::{Error, Result};
+use crypto::blake2b::Blake2b;
 use crypto::digest::Digest;
+use crypto::sha2::Sha256;
 use crypto::sha3::Sha3;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
 
-pub fn serialize<T: ?Sized>(value: &T) -> Vec<u8>
+/// 序列化内存中的值
+/// bincode 只在序列长度未知或超过大小限制时失败，这里的类型都是 derive 的结构体、数组和 Vec ，不会失败，
+/// 所以不返回 Result ，调用者（计算 hash 等）不需要处理错误
+pub fn serialize<T>(value: &T) -> Vec<u8>
 where
-    T: Serialize,
+    T: Serialize + ?Sized,
 {
-    bincode::serialize(value).unwrap()
+    bincode::serialize(value).expect("bincode cannot fail on sized values")
 }
 
-pub fn deserialize<'a, T>(bytes: &'a [u8]) -> T
+/// 数据来自数据库或网络，可能损坏
+pub fn deserialize<'a, T>(bytes: &'a [u8]) -> Result<T>
 where
     T: Deserialize<'a>,
 {
-    bincode::deserialize(bytes).unwrap()
+    Ok(bincode::deserialize(bytes)?)
 }
 
-/// 8 x 32 = 256位
-pub fn get_hash(value: &[u8]) -> [u8; 32] {
-    let mut hasher = Sha3::sha3_256();
-    hasher.input(value);
-    let mut hash = [0u8; 32];
-    hasher.result(&mut hash);
+/// 区块头、交易、merkle 树和状态树使用的 hash 函数，见 ChainConfig.hasher
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Hasher {
+    #[default]
+    #[serde(rename = "sha3_256")]
+    Sha3_256,
+    /// SHA256(SHA256(x))，与 bitcoin 相同
+    #[serde(rename = "double_sha256")]
+    DoubleSha256,
+    /// 输出 32 字节的 BLAKE2b
+    #[serde(rename = "blake2b")]
+    Blake2b,
+}
+
+impl Hasher {
+    /// 8 x 32 = 256位
+    pub fn hash(self, value: &[u8]) -> [u8; 32] {
+        let mut hash = [0u8; 32];
+        match self {
+            Hasher::Sha3_256 => digest(&mut Sha3::sha3_256(), value, &mut hash),
+            Hasher::DoubleSha256 => {
+                digest(&mut Sha256::new(), value, &mut hash);
+                let first = hash;
+                digest(&mut Sha256::new(), &first, &mut hash);
+            }
+            Hasher::Blake2b => digest(&mut Blake2b::new(32), value, &mut hash),
+        }
+
+        hash
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Hasher::Sha3_256 => "sha3_256",
+            Hasher::DoubleSha256 => "double_sha256",
+            Hasher::Blake2b => "blake2b",
+        }
+    }
+}
+
+fn digest<D: Digest>(d: &mut D, value: &[u8], out: &mut [u8; 32]) {
+    d.input(value);
+    d.result(out);
+}
+
+impl fmt::Display for Hasher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Hasher {
+    type Err = Error;
 
-    hash
+    fn from_str(s: &str) -> Result<Hasher> {
+        [Hasher::Sha3_256, Hasher::DoubleSha256, Hasher::Blake2b]
+            .iter()
+            .find(|h| h.name() == s)
+            .copied()
+            .ok_or_else(|| Error::Config(format!("unknown hasher: {}", s)))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{deserialize, serialize};
+    use super::{deserialize, serialize, Hasher};
+    use crate::utils::hex;
     // 在同一模块（要序列化）结构体中声明 derive macro
     use serde::{Deserialize, Serialize};
 
@@ -46,8 +108,57 @@ mod tests {
     fn coder_works() {
         let point = Point { x: 1, y: 1 };
         let se = serialize(&point);
-        let de: Point = deserialize(&se);
+        let de: Point = deserialize(&se).unwrap();
 
         assert_eq!(de, point);
+        // 数据损坏只返回错误
+        assert!(deserialize::<Point>(&se[..3]).is_err());
+    }
+
+    #[test]
+    fn hasher_works() {
+        let cases = [
+            (
+                Hasher::Sha3_256,
+                "a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a",
+            ),
+            (
+                Hasher::DoubleSha256,
+                "5df6e0e2761359d30a8275058e299fcc0381534545f55cf43e41983f5d4c9456",
+            ),
+            (
+                Hasher::Blake2b,
+                "0e5751c026e543b2e8ab2eb06099daa1d1e5df47778f7787faab45cdf12fe3a8",
+            ),
+        ];
+        for (hasher, expected) in cases {
+            assert_eq!(hex::encode(&hasher.hash(b"")), expected);
+            assert_eq!(hasher.to_string().parse::<Hasher>().unwrap(), hasher);
+        }
+        assert!("md5".parse::<Hasher>().is_err());
+    }
+
+    /// bitcoin 的区块 hash 是 80 字节区块头的 DoubleSha256 ，浏览器中显示的是反转后的字节
+    #[test]
+    fn bitcoin_header_works() {
+        let cases = [
+            // genesis
+            (
+                "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c",
+                "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+            ),
+            // height 1
+            (
+                "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299",
+                "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048",
+            ),
+        ];
+        for (header, expected) in cases {
+            let header = hex::decode(header).unwrap();
+            assert_eq!(header.len(), 80);
+            let mut hash = Hasher::DoubleSha256.hash(&header);
+            hash.reverse();
+            assert_eq!(hex::encode(&hash), expected);
+        }
     }
 }
@@ -0,0 +1,50 @@
+/// 十六进制编码，用于配置文件和命令行中的地址、hash
+///
+use std::fmt::Write;
+
+pub fn encode(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(s, "{:02x}", b);
+    }
+    s
+}
+
+/// 长度为奇数或含有非十六进制字符时返回 None ，可以带 0x 前缀
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// 32 字节的地址或 hash
+pub fn decode_32(s: &str) -> Option<[u8; 32]> {
+    let bytes = decode(s)?;
+    if bytes.len() != 32 {
+        return None;
+    }
+    let mut v = [0u8; 32];
+    v.copy_from_slice(&bytes);
+    Some(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, decode_32, encode};
+
+    #[test]
+    fn hex_works() {
+        assert_eq!(encode(&[0, 1, 0xab, 0xff]), "0001abff");
+        assert_eq!(decode("0001abff"), Some(vec![0, 1, 0xab, 0xff]));
+        assert_eq!(decode("0x0001ABFF"), Some(vec![0, 1, 0xab, 0xff]));
+        assert_eq!(decode("abc"), None);
+        assert_eq!(decode("zz"), None);
+        assert_eq!(decode_32(&encode(&[7; 32])), Some([7; 32]));
+        assert_eq!(decode_32("0001"), None);
+    }
+}
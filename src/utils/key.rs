@@ -1,36 +1,89 @@
-use db_key::Key;
-
-// db-key = "0.1.0"
-// pub trait Key<'a>: From<&'a [u8]> + AsRef<[u8]> {}
+// construct_uint! 生成的代码
+#![allow(clippy::assign_op_pattern, clippy::manual_range_contains)]
 
 uint::construct_uint! {
     pub struct U256(4);
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Copy, Clone)]
-pub struct MyKey {
-    pub val: U256,
+uint::construct_uint! {
+    /// 避免 U256 乘法溢出
+    pub struct U512(8);
+}
+
+/// 数据库中的列，key 的第一个字节
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Column {
+    /// block hash -> Block
+    Block,
+    /// block hash -> 累计工作量 + 区块头
+    Header,
+    /// 主链 height -> block hash
+    Height,
+    /// 主链 tx hash -> (block hash, 交易在区块中的位置)
+    Tx,
+    /// trie 节点 hash -> 节点
+    State,
+    /// tail 、数据库版本等
+    Meta,
+}
+
+impl Column {
+    pub fn prefix(self) -> u8 {
+        match self {
+            Column::Block => b'b',
+            Column::Header => b'h',
+            Column::Height => b'n',
+            Column::Tx => b't',
+            Column::State => b's',
+            Column::Meta => b'm',
+        }
+    }
 }
 
+/// 数据库中的 key ：列前缀 + id
+///
+/// 不同列的 key 不会冲突；数字用大端编码，按 key 迭代的顺序就是数字的顺序
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
+pub struct DbKey(Vec<u8>);
+
+impl DbKey {
+    pub fn new(column: Column, id: &[u8]) -> DbKey {
+        let mut k = Vec::with_capacity(1 + id.len());
+        k.push(column.prefix());
+        k.extend_from_slice(id);
+        DbKey(k)
+    }
 
-impl Key for MyKey {
-    fn from_u8(key: &[u8]) -> Self {
-        use std::mem::transmute;
+    pub fn number(column: Column, n: u64) -> DbKey {
+        Self::new(column, &n.to_be_bytes())
+    }
 
-        assert_eq!(key.len(), 32);
-        let mut result = [0u8; 32];
-        result.copy_from_slice(key);
+    pub fn meta(name: &str) -> DbKey {
+        Self::new(Column::Meta, name.as_bytes())
+    }
 
-        unsafe { transmute::<[u8; 32], Self>(result) }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
     }
+}
 
-    fn as_slice<T, F>(&self, f: F) -> T
-    where
-        F: Fn(&[u8]) -> T,
-    {
-        use std::mem::transmute;
+#[cfg(test)]
+mod tests {
+    use super::{Column, DbKey};
 
-        let val = unsafe { transmute::<_, &[u8; 32]>(self) };
-        f(val)
+    #[test]
+    fn db_key_works() {
+        assert_eq!(DbKey::new(Column::Block, &[1; 32]).as_bytes()[0], b'b');
+        assert_eq!(DbKey::meta("tail").as_bytes(), b"mtail");
+        // 大端编码，字节序与数字顺序一致
+        assert_eq!(
+            DbKey::number(Column::Height, 1).as_bytes(),
+            &[b'n', 0, 0, 0, 0, 0, 0, 0, 1]
+        );
+        assert!(DbKey::number(Column::Height, 255) < DbKey::number(Column::Height, 256));
+        assert_ne!(
+            DbKey::new(Column::Block, &[1; 32]),
+            DbKey::new(Column::State, &[1; 32])
+        );
     }
 }
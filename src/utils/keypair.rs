@@ -0,0 +1,60 @@
+/// 数字签名：Ed25519
+/// https://ed25519.cr.yp.to/
+///
+/// 公钥 32 字节，直接作为账户地址，所以验证签名只需要 Transaction.from 。
+///
+use crate::error::{Error, Result};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use rand::rngs::OsRng;
+use std::convert::TryFrom;
+
+pub struct KeyPair {
+    inner: Keypair,
+}
+
+impl KeyPair {
+    pub fn generate() -> KeyPair {
+        let mut csprng = OsRng {};
+        KeyPair {
+            inner: Keypair::generate(&mut csprng),
+        }
+    }
+
+    /// 由 32 字节私钥恢复密钥对
+    pub fn from_private(private: &[u8; 32]) -> Result<KeyPair> {
+        let secret = SecretKey::from_bytes(private).map_err(|e| Error::Account(e.to_string()))?;
+        let public = PublicKey::from(&secret);
+
+        Ok(KeyPair {
+            inner: Keypair { secret, public },
+        })
+    }
+
+    /// 公钥即地址
+    pub fn address(&self) -> [u8; 32] {
+        self.inner.public.to_bytes()
+    }
+
+    pub fn private(&self) -> [u8; 32] {
+        self.inner.secret.to_bytes()
+    }
+
+    /// 64 字节签名
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        self.inner.sign(data).to_bytes().to_vec()
+    }
+}
+
+/// 用地址（公钥）验证签名
+pub fn verify(address: &[u8; 32], data: &[u8], sign: &[u8]) -> bool {
+    let public = match PublicKey::from_bytes(address) {
+        Ok(public) => public,
+        Err(_) => return false,
+    };
+    let signature = match Signature::try_from(sign) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    public.verify(data, &signature).is_ok()
+}
@@ -0,0 +1,95 @@
+/// 固定容量的 LRU 缓存，满了以后淘汰最久没有访问的数据
+///
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+pub struct LruCache<K, V> {
+    capacity: usize,
+    /// key -> (value, 最近一次访问的序号)
+    map: HashMap<K, (V, u64)>,
+    /// 访问序号 -> key ，最小的是最久没有访问的
+    order: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K, V> LruCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn new(capacity: usize) -> LruCache<K, V> {
+        LruCache {
+            capacity,
+            map: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn get(&mut self, k: &K) -> Option<V> {
+        self.tick += 1;
+        let (v, last) = self.map.get_mut(k)?;
+        self.order.remove(last);
+        *last = self.tick;
+        self.order.insert(self.tick, k.clone());
+
+        Some(v.clone())
+    }
+
+    pub fn put(&mut self, k: K, v: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if let Some((_, last)) = self.map.insert(k.clone(), (v, self.tick)) {
+            self.order.remove(&last);
+        }
+        self.order.insert(self.tick, k);
+
+        while self.map.len() > self.capacity {
+            match self.order.pop_first() {
+                Some((_, k)) => self.map.remove(&k),
+                None => break,
+            };
+        }
+    }
+
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        let (v, last) = self.map.remove(k)?;
+        self.order.remove(&last);
+        Some(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LruCache;
+
+    #[test]
+    fn lru_works() {
+        let mut cache = LruCache::new(2);
+        cache.put(1, "a");
+        cache.put(2, "b");
+        assert_eq!(cache.get(&1), Some("a"));
+
+        // 2 最久没有访问
+        cache.put(3, "c");
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some("a"));
+        assert_eq!(cache.get(&3), Some("c"));
+
+        cache.put(3, "d");
+        assert_eq!(cache.get(&3), Some("d"));
+        assert_eq!(cache.remove(&1), Some("a"));
+        assert_eq!(cache.len(), 1);
+    }
+}
@@ -1,2 +1,7 @@
 pub mod coder;
+pub mod hex;
 pub mod key;
+pub mod keypair;
+pub mod lru;
+#[cfg(test)]
+pub mod testing;
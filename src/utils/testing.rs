@@ -0,0 +1,15 @@
+/// 测试共用的辅助函数
+///
+use std::thread;
+use std::time::Duration;
+
+/// 等待其他线程使 f 成立，大约 5 秒后超时 panic
+pub fn wait_until<F: Fn() -> bool>(f: F) {
+    for _ in 0..200 {
+        if f() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(25));
+    }
+    panic!("timeout");
+}